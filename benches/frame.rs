@@ -0,0 +1,26 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use nnnoiseless::DenoiseState;
+
+/// One second of white-ish noise, which keeps the whole network busy.
+fn noise(secs: f32) -> Vec<f32> {
+    let mut x: u32 = 1;
+    (0..((secs * 48_000.0) as usize))
+        .map(|_| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            ((x >> 16) as i16) as f32 / 4.0
+        })
+        .collect()
+}
+
+pub fn bench_frame(c: &mut Criterion) {
+    let input = noise(1.0);
+    let mut output = [0.0; DenoiseState::FRAME_SIZE];
+    let mut state = DenoiseState::new();
+    let mut frames = input.chunks_exact(DenoiseState::FRAME_SIZE).cycle();
+    c.bench_function("nnnoiseless frame/noise", |b| {
+        b.iter(|| state.process_frame(&mut output[..], frames.next().unwrap()))
+    });
+}
+
+criterion_group!(benches, bench_frame,);
+criterion_main!(benches);
@@ -1,14 +1,16 @@
-use std::borrow::Cow;
 use std::boxed::Box;
 use std::fs::File;
 use std::io::Read;
 use std::os::raw::{c_float, c_int};
+use std::sync::Arc;
 
 use libc::FILE;
 
 pub struct DenoiseState(crate::DenoiseState<'static>);
 
-pub struct RNNModel(crate::rnn::RnnModel);
+// States share the model with the `RNNModel`, so it's fine to free the model while they're still
+// using it.
+pub struct RNNModel(Arc<crate::rnn::RnnModel>);
 
 /// Return the number of samples processed at time
 ///
@@ -31,10 +33,12 @@ pub unsafe extern "C" fn rnnoise_get_size() -> c_int {
 /// It should be avoided, use directly `rnnoise_create`
 #[no_mangle]
 pub unsafe extern "C" fn rnnoise_init(st: *mut DenoiseState, model: *mut RNNModel) -> c_int {
-    let state = if model.is_null() {
-        crate::DenoiseState::default()
-    } else {
-        crate::DenoiseState::from_model_owned(Cow::Borrowed(&*(model as *mut crate::RnnModel)))
+    if st.is_null() {
+        return -1;
+    }
+    let state = match model.as_ref() {
+        None => crate::DenoiseState::default(),
+        Some(model) => crate::DenoiseState::from_model_owned(Arc::clone(&model.0).into()),
     };
 
     *st = DenoiseState(state);
@@ -47,10 +51,9 @@ pub unsafe extern "C" fn rnnoise_init(st: *mut DenoiseState, model: *mut RNNMode
 /// Use `rnnoise_destroy` to deallocate it
 #[no_mangle]
 pub unsafe extern "C" fn rnnoise_create(model: *mut RNNModel) -> *mut DenoiseState {
-    let state = if model.is_null() {
-        crate::DenoiseState::default()
-    } else {
-        crate::DenoiseState::from_model_owned(Cow::Borrowed(&*(model as *mut crate::RnnModel)))
+    let state = match model.as_ref() {
+        None => crate::DenoiseState::default(),
+        Some(model) => crate::DenoiseState::from_model_owned(Arc::clone(&model.0).into()),
     };
 
     Box::into_raw(Box::new(DenoiseState(state)))
@@ -58,10 +61,12 @@ pub unsafe extern "C" fn rnnoise_create(model: *mut RNNModel) -> *mut DenoiseSta
 
 /// Deallocate and destroy a DenoiseState
 ///
-/// Use it only on pointers returned by `rnnoise_create`.
+/// Use it only on pointers returned by `rnnoise_create`. Passing a null pointer does nothing.
 #[no_mangle]
 pub unsafe extern "C" fn rnnoise_destroy(st: *mut DenoiseState) {
-    let _ = Box::from_raw(st);
+    if !st.is_null() {
+        let _ = Box::from_raw(st);
+    }
 }
 
 /// Processes a chunk of samples.
@@ -71,25 +76,65 @@ pub unsafe extern "C" fn rnnoise_destroy(st: *mut DenoiseState) {
 /// The current output of `process_frame` depends on the current input, but also on the
 /// preceding inputs. Because of this, you might prefer to discard the very first output; it
 /// will contain some fade-in artifacts.
+///
+/// Returns the estimated probability that the input contains voice, or `-1` if any of the
+/// pointers are null or if the input contains NaNs or infinities. In case of an error, nothing
+/// is processed.
 #[no_mangle]
 pub unsafe extern "C" fn rnnoise_process_frame(
     st: *mut DenoiseState,
     out: *mut c_float,
     input: *mut c_float,
 ) -> c_float {
-    let state = st.as_mut().expect("Invalid pointer");
+    let state = match st.as_mut() {
+        Some(state) => state,
+        None => return -1.0,
+    };
+    if out.is_null() || input.is_null() {
+        return -1.0;
+    }
     let output = std::slice::from_raw_parts_mut(out, crate::DenoiseState::FRAME_SIZE);
     let input = std::slice::from_raw_parts(input, crate::DenoiseState::FRAME_SIZE);
 
-    state.0.process_frame(output, input)
+    state.0.try_process_frame(output, input).unwrap_or(-1.0)
+}
+
+/// Limits the amount by which any frequency band will be attenuated.
+///
+/// `db` is the maximum attenuation, in decibels. By default there is no limit; pass `INFINITY`
+/// to restore the default. `NAN` is treated like `INFINITY`.
+#[no_mangle]
+pub unsafe extern "C" fn rnnoise_set_max_attenuation(st: *mut DenoiseState, db: c_float) {
+    if let Some(state) = st.as_mut() {
+        state.0.set_max_attenuation(db);
+    }
+}
+
+/// Sets the balance between the denoised signal and the original signal.
+///
+/// `mix` should be between `0.0` (no denoising) and `1.0` (full denoising, the default). It can
+/// be changed between calls to `rnnoise_process_frame()`.
+#[no_mangle]
+pub unsafe extern "C" fn rnnoise_set_mix(st: *mut DenoiseState, mix: c_float) {
+    if let Some(state) = st.as_mut() {
+        state.0.set_mix(mix);
+    }
 }
 
 /// Load a custom model from a file.
+///
+/// The file can be either in nnnoiseless's binary format, or in the text format of rnnoise-nu.
+///
+/// Returns null if `file` is null, or if the model could not be read.
 #[no_mangle]
 pub unsafe extern "C" fn rnnoise_model_from_file(file: *mut FILE) -> *mut RNNModel {
     use libc::{dup, fclose, fileno};
     use std::os::unix::io::FromRawFd;
 
+    if file.is_null() {
+        return std::ptr::null_mut();
+    }
+
     let fd = dup(fileno(file));
     fclose(file);
 
@@ -98,16 +143,23 @@ pub unsafe extern "C" fn rnnoise_model_from_file(file: *mut FILE) -> *mut RNNMod
     if file.read_to_end(&mut data).is_err() {
         return std::ptr::null_mut();
     }
-    match crate::RnnModel::from_bytes(&data) {
-        Some(model) => Box::into_raw(Box::new(RNNModel(model))),
-        None => std::ptr::null_mut(),
+    let model = match std::str::from_utf8(&data) {
+        Ok(text) if text.starts_with("rnnoise-nu") => crate::RnnModel::from_rnnoise_text(text),
+        _ => crate::RnnModel::from_bytes(&data),
+    };
+    match model {
+        Ok(model) => Box::into_raw(Box::new(RNNModel(Arc::new(model)))),
+        Err(_) => std::ptr::null_mut(),
     }
 }
 
 /// Free a Custom Model
 ///
-/// See `rnnoise_model_from_file`
+/// See `rnnoise_model_from_file`. Passing a null pointer does nothing. States that were created
+/// with the model can still be used after it is freed.
 #[no_mangle]
 pub unsafe extern "C" fn rnnoise_model_free(model: *mut RNNModel) {
-    let _ = Box::from_raw(model);
+    if !model.is_null() {
+        let _ = Box::from_raw(model);
+    }
 }
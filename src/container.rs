@@ -0,0 +1,337 @@
+//! The versioned container format for model files.
+//!
+//! A container starts with a header that identifies the file and describes the model, followed
+//! by the model's layers, and finally a checksum. The layers are in almost the same format as a
+//! legacy headerless model file, except that the layer sizes are `u16`s instead of `i8`s, and
+//! that each layer's activation function is followed by the precision (as a `u8`) and the scale
+//! (as an `f32`) of its weights. All numbers are little-endian, and all strings are UTF-8 with a
+//! `u32` length prefix. The layout is:
+//!
+//! - the magic bytes `nnnm`,
+//! - the format version, as a `u16`,
+//! - the architecture, as a string,
+//! - the number of metadata entries, as a `u32`, followed by that many key-value pairs of
+//!   strings,
+//! - the length of the layer data, as a `u32`, followed by the layer data,
+//! - the CRC-32 (the same one as in zlib) of everything before it, as a `u32`.
+//!
+//! The architecture is either `rnnoise`, in which case the layer data consists of the six layers
+//! of the RNNoise network, or `graph`, in which case it describes an arbitrary graph of layers.
+//! See the `model_reader` module for the details of both.
+
+use alloc::{
+    borrow::ToOwned,
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::convert::TryFrom;
+
+use crate::ModelError;
+
+const MAGIC: &[u8; 4] = b"nnnm";
+
+/// The version of the container format that we write. We can read this version and all earlier
+/// ones.
+const VERSION: u16 = 1;
+
+/// The reason that a model couldn't be written.
+pub(crate) type WriteError = &'static str;
+
+/// The network architectures that a container can hold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Architecture {
+    /// The original RNNoise network.
+    Rnnoise,
+    /// A network described by a graph of layers.
+    Graph,
+}
+
+impl Architecture {
+    fn name(self) -> &'static str {
+        match self {
+            Architecture::Rnnoise => "rnnoise",
+            Architecture::Graph => "graph",
+        }
+    }
+}
+
+// The metadata keys with special meanings.
+const NAME: &str = "name";
+const TRAINING_DATA: &str = "training_data";
+const SAMPLE_RATE: &str = "sample_rate";
+
+/// Descriptive information about an [`RnnModel`](crate::RnnModel).
+///
+/// Legacy model files (the ones without a header) don't have any metadata, so for them all the
+/// fields are empty.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ModelMetadata {
+    /// The name of the model.
+    pub name: Option<String>,
+    /// A description of the data that the model was trained on.
+    pub training_data: Option<String>,
+    /// The sample rate of the audio that the model was trained on.
+    pub sample_rate: Option<u32>,
+    /// Any other metadata, as key-value pairs.
+    ///
+    /// The keys `name`, `training_data` and `sample_rate` are reserved for the fields above, and
+    /// models with any of them in `extra` can't be written.
+    pub extra: BTreeMap<String, String>,
+}
+
+/// The result of parsing a container.
+pub(crate) struct Container<'a> {
+    pub architecture: Architecture,
+    pub metadata: ModelMetadata,
+    /// The layer data.
+    pub payload: &'a [u8],
+    /// The offset of the layer data within the container.
+    pub payload_offset: usize,
+}
+
+/// Returns true if `data` looks like a container, as opposed to a legacy model file.
+pub(crate) fn is_container(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Parses a container, checking its header and checksum.
+pub(crate) fn parse(data: &[u8]) -> Result<Container<'_>, ModelError> {
+    let mut reader = Reader { data, offset: 0 };
+    reader.bytes(MAGIC.len())?;
+    let version = reader.u16()?;
+    if version == 0 || version > VERSION {
+        return Err(ModelError::UnsupportedVersion { version });
+    }
+
+    // Check the checksum before parsing the rest, so that a corrupted file gets reported as such
+    // instead of as some random parse error.
+    if data.len() < reader.offset + 4 {
+        return Err(ModelError::Truncated {
+            layer: "header".to_owned(),
+            offset: data.len(),
+        });
+    }
+    let (contents, checksum) = data.split_at(data.len() - 4);
+    let expected = u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+    let actual = crc32(contents);
+    if expected != actual {
+        return Err(ModelError::ChecksumMismatch { expected, actual });
+    }
+    let mut reader = Reader {
+        data: contents,
+        offset: reader.offset,
+    };
+
+    let architecture = match reader.string()? {
+        name if name == Architecture::Rnnoise.name() => Architecture::Rnnoise,
+        name if name == Architecture::Graph.name() => Architecture::Graph,
+        name => return Err(ModelError::UnsupportedArchitecture { name }),
+    };
+
+    let mut metadata = ModelMetadata::default();
+    for _ in 0..reader.u32()? {
+        let key = reader.string()?;
+        let value_offset = reader.offset;
+        let value = reader.string()?;
+        match key.as_str() {
+            NAME => metadata.name = Some(value),
+            TRAINING_DATA => metadata.training_data = Some(value),
+            SAMPLE_RATE => {
+                let rate = value.parse().map_err(|_| ModelError::InvalidMetadata {
+                    offset: value_offset,
+                })?;
+                metadata.sample_rate = Some(rate);
+            }
+            _ => {
+                metadata.extra.insert(key, value);
+            }
+        }
+    }
+
+    let len = reader.u32()? as usize;
+    let payload_offset = reader.offset;
+    let payload = reader.bytes(len)?;
+    if reader.offset != contents.len() {
+        return Err(ModelError::TrailingBytes {
+            offset: reader.offset,
+            len: contents.len() - reader.offset,
+        });
+    }
+
+    Ok(Container {
+        architecture,
+        metadata,
+        payload,
+        payload_offset,
+    })
+}
+
+/// Writes a container with the given architecture, metadata and layer data.
+pub(crate) fn write(
+    architecture: Architecture,
+    metadata: &ModelMetadata,
+    payload: &[u8],
+) -> Result<Vec<u8>, WriteError> {
+    let mut entries: Vec<(&str, String)> = Vec::new();
+    if let Some(name) = &metadata.name {
+        entries.push((NAME, name.clone()));
+    }
+    if let Some(training_data) = &metadata.training_data {
+        entries.push((TRAINING_DATA, training_data.clone()));
+    }
+    if let Some(rate) = metadata.sample_rate {
+        entries.push((SAMPLE_RATE, rate.to_string()));
+    }
+    for (key, value) in &metadata.extra {
+        // When reading, these would end up in the typed fields instead of in `extra`.
+        if [NAME, TRAINING_DATA, SAMPLE_RATE].contains(&key.as_str()) {
+            return Err("extra metadata uses a reserved key");
+        }
+        entries.push((key, value.clone()));
+    }
+
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+    write_string(&mut buf, architecture.name())?;
+    write_len(&mut buf, entries.len())?;
+    for (key, value) in &entries {
+        write_string(&mut buf, key)?;
+        write_string(&mut buf, value)?;
+    }
+    write_len(&mut buf, payload.len())?;
+    buf.extend_from_slice(payload);
+    let checksum = crc32(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+
+    Ok(buf)
+}
+
+pub(crate) fn write_len(buf: &mut Vec<u8>, len: usize) -> Result<(), WriteError> {
+    let len = u32::try_from(len).map_err(|_| "model data is too long")?;
+    buf.extend_from_slice(&len.to_le_bytes());
+    Ok(())
+}
+
+pub(crate) fn write_string(buf: &mut Vec<u8>, s: &str) -> Result<(), WriteError> {
+    write_len(buf, s.len())?;
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ModelError> {
+        if self.data.len() - self.offset < len {
+            return Err(ModelError::Truncated {
+                layer: "header".to_owned(),
+                offset: self.offset,
+            });
+        }
+        let ret = &self.data[self.offset..(self.offset + len)];
+        self.offset += len;
+        Ok(ret)
+    }
+
+    fn u16(&mut self) -> Result<u16, ModelError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ModelError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn string(&mut self) -> Result<String, ModelError> {
+        let len = self.u32()? as usize;
+        let offset = self.offset;
+        let b = self.bytes(len)?;
+        String::from_utf8(b.to_owned()).map_err(|_| ModelError::InvalidMetadata { offset })
+    }
+}
+
+/// Computes the CRC-32 checksum used by zlib (and PNG, and lots of other things).
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut metadata = ModelMetadata {
+            name: Some("test".to_owned()),
+            training_data: Some("nothing much".to_owned()),
+            sample_rate: Some(48_000),
+            extra: BTreeMap::new(),
+        };
+        metadata.extra.insert("author".to_owned(), "me".to_owned());
+
+        let buf = write(Architecture::Graph, &metadata, &[1, 2, 3]).unwrap();
+        let container = parse(&buf).unwrap();
+        assert_eq!(container.architecture, Architecture::Graph);
+        assert_eq!(container.metadata, metadata);
+        assert_eq!(container.payload, &[1, 2, 3]);
+        assert_eq!(&buf[container.payload_offset..][..3], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn reserved_keys() {
+        for key in &[NAME, TRAINING_DATA, SAMPLE_RATE] {
+            let mut metadata = ModelMetadata::default();
+            metadata.extra.insert(key.to_string(), "extra".to_owned());
+            assert!(write(Architecture::Rnnoise, &metadata, &[]).is_err());
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let buf = write(Architecture::Rnnoise, &ModelMetadata::default(), &[1, 2, 3]).unwrap();
+
+        let mut corrupted = buf.clone();
+        corrupted[12] ^= 1;
+        assert!(matches!(
+            parse(&corrupted),
+            Err(ModelError::ChecksumMismatch { .. })
+        ));
+
+        let mut future = buf.clone();
+        future[4] = 2;
+        assert!(matches!(
+            parse(&future),
+            Err(ModelError::UnsupportedVersion { version: 2 })
+        ));
+
+        assert_eq!(
+            parse(&buf[..5]).err(),
+            Some(ModelError::Truncated {
+                layer: "header".to_owned(),
+                offset: 4
+            })
+        );
+    }
+}
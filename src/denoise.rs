@@ -1,6 +1,13 @@
-use std::borrow::Cow;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 
-use crate::{RnnModel, FRAME_SIZE, FREQ_SIZE, NB_BANDS};
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
+
+use crate::error::{check_finite, check_len, restore_buf};
+use crate::features::FeaturesSnapshot;
+use crate::resample::{Resampler, ResamplerSnapshot};
+use crate::rnn::RnnState;
+use crate::{Complex, Error, ModelRef, RnnModel, FRAME_SIZE, FREQ_SIZE, NB_BANDS};
 
 /// This is the low-level entry-point into `nnnoiseless`: by using the `DenoiseState` directly,
 /// you can denoise your audio while keeping copying to a minimum. For a higher-level
@@ -9,6 +16,19 @@ use crate::{RnnModel, FRAME_SIZE, FREQ_SIZE, NB_BANDS};
 /// This struct directly contains various memory buffers that are used while denoising. As such,
 /// this is quite a large struct, and should probably be kept behind some kind of pointer.
 ///
+/// # Real-time use
+///
+/// All the memory that a `DenoiseState` needs is allocated when it is created, and any global
+/// tables are initialized then. After that, the processing methods ([`DenoiseState::process_frame`]
+/// and its variants, including the `try_` ones) never allocate, take locks or initialize
+/// anything lazily, so they are safe to call from a real-time audio thread. The same goes for
+/// [`StreamingDenoiser::process`](crate::StreamingDenoiser::process) and
+/// [`StreamingDenoiser::flush`](crate::StreamingDenoiser::flush), as long as their output
+/// `Vec` has enough spare capacity.
+///
+/// The methods that change the configuration (such as [`DenoiseState::set_sample_rate`] and
+/// [`DenoiseState::set_model`]) may allocate, so they should be called ahead of time.
+///
 /// # Example
 ///
 /// ```rust
@@ -37,16 +57,105 @@ use crate::{RnnModel, FRAME_SIZE, FREQ_SIZE, NB_BANDS};
 pub struct DenoiseState<'model> {
     /// Most recent gains that we applied.
     lastg: [f32; crate::NB_BANDS],
-    rnn: crate::rnn::RnnState<'model>,
+    /// The smallest gain that we will apply to any band.
+    min_gain: f32,
+    /// The proportion of denoised signal in the output.
+    mix: f32,
+    /// A copy of the spectrum of the current frame, before denoising.
+    dry: [Complex; FREQ_SIZE],
+    /// If the sample rate is less than 48kHz, this converts to and from 48kHz.
+    resampler: Option<Resampler>,
+    /// The range of the input and output samples.
+    scale: SampleScale,
+    rnn: RnnState<'model>,
+    /// The model that we're fading out, if we're in the middle of a crossfade.
+    fade: Option<Crossfade<'model>>,
     feat: crate::features::DenoiseFeatures,
 }
 
+/// The history of the signal processed by a [`DenoiseState`], which determines how it will
+/// process the next frame.
+///
+/// A snapshot contains the state of the neural network, the most recent gains, and the parts of
+/// the previous input that are needed for computing features and for resampling. It doesn't
+/// contain the model or any of the settings (such as the sample rate), so it can only be
+/// restored into a `DenoiseState` that has the same model and sample rate as the one it was
+/// taken from. See [`DenoiseState::snapshot`].
+///
+/// If the `serde` feature is enabled, snapshots can be serialized and deserialized.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DenoiseSnapshot {
+    lastg: Vec<f32>,
+    rnn: Vec<Vec<f32>>,
+    features: FeaturesSnapshot,
+    resampler: Option<ResamplerSnapshot>,
+}
+
+/// A model that is being faded out in favor of the current one. See
+/// [`DenoiseState::crossfade_to_model`].
+#[derive(Clone)]
+struct Crossfade<'model> {
+    rnn: RnnState<'model>,
+    /// The length of the crossfade, in frames.
+    frames: usize,
+    /// The number of frames of the crossfade that have been processed so far.
+    elapsed: usize,
+}
+
+/// Information about a single frame of audio, as returned by
+/// [`DenoiseState::process_frame_with_info`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameInfo {
+    /// The gains that were applied to each frequency band. These are all `1.0` if the frame was
+    /// silent.
+    ///
+    /// These gains take into account the maximum attenuation (see
+    /// [`DenoiseState::set_max_attenuation`]), but not the wet/dry mix.
+    pub gains: [f32; NB_BANDS],
+    /// The estimated probability that this frame contains voice. This is the same as the return
+    /// value of [`DenoiseState::process_frame`].
+    ///
+    /// If the model doesn't estimate voice activity (see [`RnnModel::vad_layer`]), this is `1.0`
+    /// unless the frame was silent.
+    pub vad: f32,
+    /// The detected pitch period, in samples at 48kHz (regardless of the sample rate that
+    /// the `DenoiseState` is using).
+    pub pitch_period: usize,
+    /// True if this frame was considered to be silent, in which case it was not denoised.
+    pub silence: bool,
+    /// The energy of the input in each frequency band.
+    pub band_energy: [f32; NB_BANDS],
+    /// The energy of the pitch-period-lagged input in each frequency band.
+    pub pitch_band_energy: [f32; NB_BANDS],
+    /// The normalized correlation between the input and the pitch-period-lagged input, in each
+    /// frequency band.
+    pub pitch_correlation: [f32; NB_BANDS],
+}
+
+/// The range of the `f32` samples passed to [`DenoiseState::process_frame`].
+///
+/// See [`DenoiseState::set_sample_scale`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SampleScale {
+    /// Samples are in the range of an `i16`, that is `[-32768.0, 32767.0]`. This is the default,
+    /// for compatibility with RNNoise.
+    #[default]
+    I16,
+    /// Samples are in the range `[-1.0, 1.0]`, as is usual for floating-point PCM.
+    Normalized,
+}
+
 impl DenoiseState<'static> {
-    /// A `DenoiseState` processes this many samples at a time.
+    /// A `DenoiseState` processes this many samples at a time (at the default sample rate of
+    /// 48kHz; see [`DenoiseState::frame_size`]).
     pub const FRAME_SIZE: usize = FRAME_SIZE;
 
+    /// The sample rates supported by [`DenoiseState::set_sample_rate`].
+    pub const SAMPLE_RATES: &'static [u32] = &[48_000, 24_000, 16_000, 12_000, 8_000];
+
     pub(crate) fn default() -> Self {
-        DenoiseState::from_model_owned(Cow::Owned(RnnModel::default()))
+        DenoiseState::from_model_owned(RnnModel::default().into())
     }
 
     /// Creates a new `DenoiseState`.
@@ -59,7 +168,16 @@ impl DenoiseState<'static> {
     /// The main difference between this method and `DenoiseState::with_model` is that here
     /// `DenoiseState` will own the model; this might be more convenient.
     pub fn from_model(model: RnnModel) -> Box<DenoiseState<'static>> {
-        Box::new(DenoiseState::from_model_owned(Cow::Owned(model)))
+        Box::new(DenoiseState::from_model_owned(model.into()))
+    }
+
+    /// Creates a new `DenoiseState` sharing a custom model.
+    ///
+    /// Unlike `DenoiseState::with_model`, this doesn't borrow the model; unlike
+    /// `DenoiseState::from_model`, cloning the `DenoiseState` doesn't clone the model. This makes
+    /// it a good choice for running many `DenoiseState`s with the same model.
+    pub fn from_shared_model(model: Arc<RnnModel>) -> Box<DenoiseState<'static>> {
+        Box::new(DenoiseState::from_model_owned(model.into()))
     }
 }
 
@@ -70,29 +188,400 @@ impl<'model> DenoiseState<'model> {
     /// `DenoiseState` will borrow the model; this might create some lifetime-related pain, but
     /// it means that the same model can be shared between multiple `DenoiseState`s.
     pub fn with_model(model: &'model RnnModel) -> Box<DenoiseState<'model>> {
-        Box::new(DenoiseState::from_model_owned(Cow::Borrowed(model)))
+        Box::new(DenoiseState::from_model_owned(model.into()))
     }
 
-    pub(crate) fn from_model_owned(model: Cow<'model, RnnModel>) -> DenoiseState<'model> {
+    pub(crate) fn from_model_owned(model: ModelRef<'model>) -> DenoiseState<'model> {
         DenoiseState {
             lastg: [0.0; NB_BANDS],
-            rnn: crate::rnn::RnnState::new(model),
+            min_gain: 0.0,
+            mix: 1.0,
+            dry: [Complex::default(); FREQ_SIZE],
+            resampler: None,
+            scale: SampleScale::I16,
+            rnn: RnnState::new(model),
+            fade: None,
             feat: crate::features::DenoiseFeatures::new(),
         }
     }
 
+    /// Sets the sample rate of the input and output.
+    ///
+    /// The default sample rate is 48kHz, and the other supported sample rates are listed in
+    /// [`DenoiseState::SAMPLE_RATES`]. At lower sample rates, the audio is internally converted
+    /// to and from 48kHz, which increases the latency a little (see [`DenoiseState::latency`]).
+    /// Note that changing the sample rate also changes the frame size.
+    ///
+    /// This should be called before processing any audio; changing the sample rate in the middle
+    /// of a stream will cause a glitch.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` is not one of the supported sample rates.
+    pub fn set_sample_rate(&mut self, rate: u32) -> &mut Self {
+        assert!(
+            DenoiseState::SAMPLE_RATES.contains(&rate),
+            "unsupported sample rate {}",
+            rate
+        );
+        let factor = (48_000 / rate) as usize;
+        self.resampler = if factor == 1 {
+            None
+        } else {
+            Some(Resampler::new(factor))
+        };
+        self
+    }
+
+    /// Returns the sample rate of the input and output.
+    pub fn sample_rate(&self) -> u32 {
+        (48_000 * self.frame_size() / FRAME_SIZE) as u32
+    }
+
+    /// Returns the number of samples that [`DenoiseState::process_frame`] processes at a time.
+    ///
+    /// This is `DenoiseState::FRAME_SIZE` (which is 10ms of audio at 48kHz) unless the sample rate
+    /// has been changed, in which case it is still 10ms of audio at the new sample rate.
+    pub fn frame_size(&self) -> usize {
+        match &self.resampler {
+            Some(resampler) => resampler.frame_size(),
+            None => FRAME_SIZE,
+        }
+    }
+
+    /// Returns the number of samples by which the output of [`DenoiseState::process_frame`] lags
+    /// its input.
+    ///
+    /// At 48kHz, this is `DenoiseState::FRAME_SIZE`.
+    pub fn latency(&self) -> usize {
+        match &self.resampler {
+            Some(resampler) => resampler.frame_size() + resampler.delay(),
+            None => FRAME_SIZE,
+        }
+    }
+
+    /// Limits the amount by which any frequency band will be attenuated.
+    ///
+    /// `db` is the maximum attenuation, in decibels; for example, if `db` is `20.0` then every
+    /// band will retain at least a tenth of its amplitude, no matter how noisy it looks. This
+    /// is useful if you find that completely removing the background noise sounds unnatural.
+    /// The default is `f32::INFINITY`, meaning that there is no limit. Negative values are
+    /// treated as zero (which effectively disables denoising), and NaN is treated as infinity.
+    pub fn set_max_attenuation(&mut self, db: f32) -> &mut Self {
+        let db = if db.is_nan() {
+            f32::INFINITY
+        } else {
+            db.max(0.0)
+        };
+        self.min_gain = 10.0f32.powf(-db / 20.0);
+        self
+    }
+
+    /// Returns the maximum attenuation, in decibels, that we will apply to any frequency band.
+    ///
+    /// See [`DenoiseState::set_max_attenuation`].
+    pub fn max_attenuation(&self) -> f32 {
+        -20.0 * self.min_gain.log10()
+    }
+
+    /// Sets the balance between the denoised signal and the original signal.
+    ///
+    /// The output will consist of `mix` times the denoised signal, plus `1.0 - mix` times the
+    /// original signal, so `1.0` (the default) means full denoising and `0.0` means no denoising.
+    /// The mixing happens in the frequency domain, so the two signals are correctly aligned
+    /// in time. (The original signal still goes through the high-pass filter, though.) Values
+    /// outside the range `[0.0, 1.0]` will be clamped.
+    ///
+    /// This can be changed at any time, and it will take effect starting from the next frame.
+    pub fn set_mix(&mut self, mix: f32) -> &mut Self {
+        self.mix = mix.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Returns the balance between the denoised signal and the original signal.
+    ///
+    /// See [`DenoiseState::set_mix`].
+    pub fn mix(&self) -> f32 {
+        self.mix
+    }
+
+    /// Switches to a different model, starting from the next frame.
+    ///
+    /// `model` can be an [`RnnModel`], a reference to one or an `Arc<RnnModel>` (see
+    /// [`ModelRef`]). The history of the input signal is kept, so this causes less of a glitch than
+    /// starting again with a new `DenoiseState`. However, the new model starts without any
+    /// memory of the signal; for a smoother transition, see
+    /// [`DenoiseState::crossfade_to_model`].
+    pub fn set_model(&mut self, model: impl Into<ModelRef<'model>>) -> &mut Self {
+        self.rnn = RnnState::new(model.into());
+        self.fade = None;
+        self
+    }
+
+    /// Returns the model that is being used for denoising.
+    ///
+    /// During a crossfade (see [`DenoiseState::crossfade_to_model`]), this is the model that is
+    /// being faded in.
+    pub fn model(&self) -> &RnnModel {
+        self.rnn.model()
+    }
+
+    /// Gradually switches to a different model over the next `frames` frames.
+    ///
+    /// During the crossfade, both the current model and `model` run on every frame, and the
+    /// band gains and voice activity probabilities that they produce are linearly interpolated
+    /// between them, moving one step further towards `model` with every frame. This gives the
+    /// new model a chance to adapt to the signal before it takes over completely, but it makes
+    /// processing about twice as expensive until the crossfade is done. Silent frames don't run
+    /// the models, so they don't count towards `frames`.
+    ///
+    /// `model` can be an [`RnnModel`], a reference to one or an `Arc<RnnModel>` (see
+    /// [`ModelRef`]). If another crossfade is in progress, the model that was being faded out is
+    /// dropped, and the new crossfade starts from the model that was being faded in. If
+    /// `frames` is zero, this is the same as [`DenoiseState::set_model`].
+    pub fn crossfade_to_model(
+        &mut self,
+        model: impl Into<ModelRef<'model>>,
+        frames: usize,
+    ) -> &mut Self {
+        let old = core::mem::replace(&mut self.rnn, RnnState::new(model.into()));
+        self.fade = if frames > 0 {
+            Some(Crossfade {
+                rnn: old,
+                frames,
+                elapsed: 0,
+            })
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Forgets the history of the signal, so that the next frame is processed as if it were the
+    /// first one.
+    ///
+    /// This is useful for processing an unrelated signal (say, the next utterance) without
+    /// creating a new `DenoiseState`. The model and all the settings are kept, but any crossfade
+    /// in progress (see [`DenoiseState::crossfade_to_model`]) finishes immediately.
+    pub fn reset(&mut self) {
+        self.lastg = [0.0; NB_BANDS];
+        self.rnn.reset();
+        self.fade = None;
+        self.feat.reset();
+        if let Some(resampler) = &mut self.resampler {
+            resampler.reset();
+        }
+    }
+
+    /// Saves the history of the signal, so that it can be restored later with
+    /// [`DenoiseState::restore`].
+    ///
+    /// For example, this can be used to move the processing of a signal to a different
+    /// `DenoiseState` (or, with the `serde` feature, to a different process) without any
+    /// glitches, or to try out different ways of processing the signal starting from the same
+    /// point.
+    ///
+    /// If a crossfade is in progress (see [`DenoiseState::crossfade_to_model`]), only the state
+    /// of the model that is being faded in is saved.
+    pub fn snapshot(&self) -> DenoiseSnapshot {
+        DenoiseSnapshot {
+            lastg: self.lastg.to_vec(),
+            rnn: self.rnn.snapshot(),
+            features: self.feat.snapshot(),
+            resampler: self.resampler.as_ref().map(|r| r.snapshot()),
+        }
+    }
+
+    /// Restores the history of the signal from a snapshot taken by [`DenoiseState::snapshot`].
+    ///
+    /// The snapshot must have been taken from a `DenoiseState` with the same model and sample
+    /// rate as this one; otherwise, this returns [`Error::InvalidSnapshot`] and nothing is
+    /// changed. Any crossfade in progress finishes immediately.
+    pub fn restore(&mut self, snapshot: &DenoiseSnapshot) -> Result<(), Error> {
+        // Restore into copies first, so that nothing changes if the snapshot is invalid.
+        let mut lastg = [0.0; NB_BANDS];
+        restore_buf(&mut lastg, &snapshot.lastg)?;
+        let mut feat = self.feat.clone();
+        feat.restore(&snapshot.features)?;
+        let mut resampler = self.resampler.clone();
+        match (&mut resampler, &snapshot.resampler) {
+            (Some(resampler), Some(saved)) => resampler.restore(saved)?,
+            (None, None) => {}
+            _ => return Err(Error::InvalidSnapshot),
+        }
+        self.rnn.restore(&snapshot.rnn)?;
+
+        self.lastg = lastg;
+        self.feat = feat;
+        self.resampler = resampler;
+        self.fade = None;
+        Ok(())
+    }
+
+    /// Sets the range of the samples passed to [`DenoiseState::process_frame`].
+    ///
+    /// By default, the samples are expected to be in the range of an `i16`, as they are in
+    /// RNNoise. If your audio is in the range `[-1.0, 1.0]` instead, set this to
+    /// `SampleScale::Normalized`; the output will then be in the same range. Getting this wrong
+    /// doesn't cause any errors, but the denoising won't work: audio in the range
+    /// `[-1.0, 1.0]` looks like silence to a denoiser expecting the range of an `i16`.
+    pub fn set_sample_scale(&mut self, scale: SampleScale) -> &mut Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Returns the range of the samples passed to [`DenoiseState::process_frame`].
+    ///
+    /// See [`DenoiseState::set_sample_scale`].
+    pub fn sample_scale(&self) -> SampleScale {
+        self.scale
+    }
+
     /// Processes a chunk of samples.
     ///
-    /// Both `output` and `input` should be slices of length `DenoiseState::FRAME_SIZE`, and they
-    /// are assumed to be in 16-bit, 48kHz signed PCM format. Note that although the input and
+    /// Both `output` and `input` should be slices of length `DenoiseState::FRAME_SIZE` (or
+    /// [`DenoiseState::frame_size`], if you changed the sample rate). By default, they are
+    /// assumed to be in 16-bit signed PCM format. Note that although the input and
     /// output are `f32`s, they are supposed to come from 16-bit integers. In particular, they
     /// should be in the range `[-32768.0, 32767.0]` instead of the range `[-1.0, 1.0]` which
-    /// is more common for floating-point PCM.
+    /// is more common for floating-point PCM. If your samples are in the range `[-1.0, 1.0]`,
+    /// see [`DenoiseState::set_sample_scale`].
     ///
     /// The current output of `process_frame` depends on the current input, but also on the
     /// preceding inputs. Because of this, you might prefer to discard the very first output; it
     /// will contain some fade-in artifacts.
+    ///
+    /// The return value is the estimated probability that the input contains voice.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `output` or `input` has the wrong length. See [`DenoiseState::try_process_frame`]
+    /// for a version that returns an error instead.
     pub fn process_frame(&mut self, output: &mut [f32], input: &[f32]) -> f32 {
+        self.process_frame_with_info(output, input).vad
+    }
+
+    /// Processes a chunk of samples, after checking that the arguments are valid.
+    ///
+    /// This is like [`DenoiseState::process_frame`], except that it returns an error if `output`
+    /// or `input` has the wrong length, or if `input` contains NaNs or infinities. (Such samples
+    /// would otherwise corrupt the internal state of the `DenoiseState`, ruining all of the
+    /// subsequent output.) If there is an error, nothing is processed and the state of the
+    /// `DenoiseState` is unchanged.
+    pub fn try_process_frame(&mut self, output: &mut [f32], input: &[f32]) -> Result<f32, Error> {
+        self.try_process_frame_with_info(output, input)
+            .map(|info| info.vad)
+    }
+
+    /// Processes a chunk of samples, returning some information about the analysis.
+    ///
+    /// This does exactly the same processing as [`DenoiseState::process_frame`], but it returns
+    /// more details about what happened. This might be useful for visualizations, or for
+    /// monitoring the denoising quality.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `output` or `input` has the wrong length.
+    pub fn process_frame_with_info(&mut self, output: &mut [f32], input: &[f32]) -> FrameInfo {
+        self.check_lengths(output, input);
+        match self.scale {
+            SampleScale::I16 => self.process_frame_resampled(output, input),
+            SampleScale::Normalized => {
+                let mut in_buf = [0.0; FRAME_SIZE];
+                let in_buf = &mut in_buf[..input.len()];
+                for (x, &y) in in_buf.iter_mut().zip(input) {
+                    *x = y * 32768.0;
+                }
+                let info = self.process_frame_resampled(output, in_buf);
+                for x in output.iter_mut() {
+                    *x /= 32768.0;
+                }
+                info
+            }
+        }
+    }
+
+    /// Processes a chunk of samples, after checking that the arguments are valid.
+    ///
+    /// This is like [`DenoiseState::process_frame_with_info`], with the same error checking as
+    /// [`DenoiseState::try_process_frame`].
+    pub fn try_process_frame_with_info(
+        &mut self,
+        output: &mut [f32],
+        input: &[f32],
+    ) -> Result<FrameInfo, Error> {
+        check_len(output, self.frame_size())?;
+        check_len(input, self.frame_size())?;
+        check_finite(input)?;
+        Ok(self.process_frame_with_info(output, input))
+    }
+
+    /// Processes a chunk of 16-bit samples.
+    ///
+    /// This is like [`DenoiseState::process_frame`], except that the input and output are
+    /// `i16`s (and the sample scale set by [`DenoiseState::set_sample_scale`] is ignored). The
+    /// output is rounded to the nearest integer, and clipped if necessary.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `output` or `input` has the wrong length. See
+    /// [`DenoiseState::try_process_frame_i16`] for a version that returns an error instead.
+    pub fn process_frame_i16(&mut self, output: &mut [i16], input: &[i16]) -> f32 {
+        self.check_lengths(output, input);
+        let mut in_buf = [0.0; FRAME_SIZE];
+        let mut out_buf = [0.0; FRAME_SIZE];
+        let in_buf = &mut in_buf[..input.len()];
+        let out_buf = &mut out_buf[..output.len()];
+        for (x, &y) in in_buf.iter_mut().zip(input) {
+            *x = y as f32;
+        }
+        let vad = self.process_frame_resampled(out_buf, in_buf).vad;
+        for (x, &y) in output.iter_mut().zip(out_buf.iter()) {
+            *x = y.max(i16::MIN as f32).min(i16::MAX as f32).round() as i16;
+        }
+        vad
+    }
+
+    /// Processes a chunk of 16-bit samples, after checking that the arguments have the right
+    /// length.
+    ///
+    /// See [`DenoiseState::process_frame_i16`] and [`DenoiseState::try_process_frame`].
+    pub fn try_process_frame_i16(
+        &mut self,
+        output: &mut [i16],
+        input: &[i16],
+    ) -> Result<f32, Error> {
+        check_len(output, self.frame_size())?;
+        check_len(input, self.frame_size())?;
+        Ok(self.process_frame_i16(output, input))
+    }
+
+    fn check_lengths<T>(&self, output: &[T], input: &[T]) {
+        if let Err(e) =
+            check_len(output, self.frame_size()).and(check_len(input, self.frame_size()))
+        {
+            panic!("{}", e);
+        }
+    }
+
+    /// Processes a chunk of samples in the range of an `i16`, at the current sample rate.
+    fn process_frame_resampled(&mut self, output: &mut [f32], input: &[f32]) -> FrameInfo {
+        if let Some(resampler) = &mut self.resampler {
+            let mut in_buf = [0.0; FRAME_SIZE];
+            let mut out_buf = [0.0; FRAME_SIZE];
+            resampler.upsample(&mut in_buf, input);
+            let info = self.process_frame_48k(&mut out_buf, &in_buf);
+            // The borrow checker doesn't know that `process_frame_48k` leaves the resampler alone.
+            if let Some(resampler) = &mut self.resampler {
+                resampler.downsample(output, &out_buf);
+            }
+            info
+        } else {
+            self.process_frame_48k(output, input)
+        }
+    }
+
+    fn process_frame_48k(&mut self, output: &mut [f32], input: &[f32]) -> FrameInfo {
         let mut g = [0.0; NB_BANDS];
         let mut gf = [1.0; FREQ_SIZE];
         let mut vad_prob = [0.0];
@@ -100,19 +589,53 @@ impl<'model> DenoiseState<'model> {
         self.feat.shift_and_filter_input(input);
         let silence = self.feat.compute_frame_features();
         if !silence {
+            let mix = self.mix;
+            if mix < 1.0 {
+                self.dry = self.feat.x;
+            }
+
             self.rnn
                 .compute(&mut g[..], &mut vad_prob[..], self.feat.features());
+            if let Some(fade) = &mut self.fade {
+                let mut old_g = [0.0; NB_BANDS];
+                let mut old_vad_prob = [0.0];
+                fade.rnn
+                    .compute(&mut old_g[..], &mut old_vad_prob[..], self.feat.features());
+                fade.elapsed += 1;
+                let t = fade.elapsed as f32 / fade.frames as f32;
+                for (g, old_g) in g.iter_mut().zip(&old_g) {
+                    *g = t * *g + (1.0 - t) * old_g;
+                }
+                vad_prob[0] = t * vad_prob[0] + (1.0 - t) * old_vad_prob[0];
+                if fade.elapsed == fade.frames {
+                    self.fade = None;
+                }
+            }
             self.feat.pitch_filter(&g);
             for i in 0..NB_BANDS {
-                g[i] = g[i].max(0.6 * self.lastg[i]);
+                g[i] = g[i].max(0.6 * self.lastg[i]).max(self.min_gain);
                 self.lastg[i] = g[i];
             }
             crate::interp_band_gain(&mut gf[..], &g[..]);
             self.feat.apply_gain(&gf);
+
+            if mix < 1.0 {
+                for (x, &dry) in self.feat.x.iter_mut().zip(&self.dry) {
+                    *x = *x * mix + dry * (1.0 - mix);
+                }
+            }
         }
 
         self.feat.frame_synthesis(output);
-        vad_prob[0]
+        FrameInfo {
+            gains: if silence { [1.0; NB_BANDS] } else { g },
+            vad: vad_prob[0],
+            pitch_period: self.feat.pitch_period(),
+            silence,
+            band_energy: self.feat.ex,
+            pitch_band_energy: self.feat.ep,
+            pitch_correlation: self.feat.exp,
+        }
     }
 }
 
@@ -123,4 +646,323 @@ mod tests {
     extern crate static_assertions as sa;
 
     sa::assert_impl_all!(DenoiseState: Send, Sync);
+
+    fn reference_input() -> Vec<f32> {
+        include_bytes!("../test_data/testing.raw")
+            .chunks_exact(2)
+            .map(|x| i16::from_le_bytes([x[0], x[1]]) as f32)
+            .collect()
+    }
+
+    fn energy(xs: &[f32]) -> f32 {
+        xs.iter().map(|x| x * x).sum()
+    }
+
+    fn denoise(state: &mut DenoiseState, input: &[f32]) -> Vec<f32> {
+        let mut output = vec![0.0; input.len()];
+        for (out, chunk) in output
+            .chunks_exact_mut(FRAME_SIZE)
+            .zip(input.chunks_exact(FRAME_SIZE))
+        {
+            state.process_frame(out, chunk);
+        }
+        output
+    }
+
+    // Returns the smallest ratio between output and input energy of any frame. The output is
+    // delayed by one frame relative to the input, and we skip the first few frames while the
+    // denoiser is warming up.
+    fn min_energy_ratio(input: &[f32], output: &[f32]) -> f32 {
+        let skip = FRAME_SIZE * 10;
+        input[skip..]
+            .chunks_exact(FRAME_SIZE)
+            .zip(output[(skip + FRAME_SIZE)..].chunks_exact(FRAME_SIZE))
+            .map(|(i, o)| energy(o) / energy(i))
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    #[test]
+    fn frame_info() {
+        let input = reference_input();
+        let mut state = DenoiseState::new();
+        let mut out = [0.0; FRAME_SIZE];
+        let mut infos = Vec::new();
+        for chunk in input.chunks_exact(FRAME_SIZE) {
+            infos.push(state.process_frame_with_info(&mut out, chunk));
+        }
+
+        let mut state = DenoiseState::new();
+        for (chunk, info) in input.chunks_exact(FRAME_SIZE).zip(&infos) {
+            assert_eq!(state.process_frame(&mut out, chunk), info.vad);
+            if info.silence {
+                assert_eq!(info.gains, [1.0; NB_BANDS]);
+            }
+            assert!(info.gains.iter().all(|&g| (0.0..=1.0).contains(&g)));
+            assert!(info.pitch_period >= crate::PITCH_MIN_PERIOD);
+            assert!(info.pitch_period <= crate::PITCH_MAX_PERIOD);
+        }
+        assert!(infos.iter().any(|info| info.vad > 0.9));
+        assert!(infos.iter().any(|info| info.vad < 0.1));
+    }
+
+    #[test]
+    fn mix() {
+        let input = reference_input();
+        let mut state = DenoiseState::new();
+        state.set_mix(0.0);
+        let output = denoise(&mut state, &input);
+
+        // With no denoising, the output should just be the high-pass filtered input, delayed by
+        // one frame.
+        let mut filtered = vec![0.0; input.len()];
+        crate::util::BIQUAD_HP.filter(&mut filtered, &mut [0.0; 2], &input);
+        let len = (input.len() / FRAME_SIZE - 1) * FRAME_SIZE;
+        let diff: Vec<f32> = output[FRAME_SIZE..(FRAME_SIZE + len)]
+            .iter()
+            .zip(&filtered[..len])
+            .map(|(x, y)| x - y)
+            .collect();
+        assert!(energy(&diff) < 1e-6 * energy(&filtered[..len]));
+
+        // Mixing halfway should land somewhere in between.
+        let mut state = DenoiseState::new();
+        let denoised = denoise(&mut state, &input);
+        let mut state = DenoiseState::new();
+        state.set_mix(0.5);
+        let half = denoise(&mut state, &input);
+        assert!(energy(&denoised) < energy(&half));
+        assert!(energy(&half) < energy(&output));
+    }
+
+    #[test]
+    fn max_attenuation() {
+        let input = reference_input();
+        let mut state = DenoiseState::new();
+        assert_eq!(state.max_attenuation(), f32::INFINITY);
+        let unlimited = denoise(&mut state, &input);
+
+        let mut state = DenoiseState::new();
+        state.set_max_attenuation(10.0);
+        assert!((state.max_attenuation() - 10.0).abs() < 1e-4);
+        let limited = denoise(&mut state, &input);
+
+        // NaN doesn't turn off denoising, but removes the limit.
+        state.set_max_attenuation(f32::NAN);
+        assert_eq!(state.max_attenuation(), f32::INFINITY);
+
+        // Without a limit, the noisiest frames get attenuated by more than 20dB. With a limit of
+        // 10dB they lose much less (but still more than 10dB, because of the high-pass filter).
+        assert!(min_energy_ratio(&input, &unlimited) < 0.01);
+        assert!(min_energy_ratio(&input, &limited) > 0.02);
+    }
+
+    #[test]
+    fn sample_scale() {
+        let input = reference_input();
+        let mut state = DenoiseState::new();
+        assert_eq!(state.sample_scale(), SampleScale::I16);
+        let expected = denoise(&mut state, &input);
+
+        // Normalized samples should give the same output, just scaled down.
+        let normalized: Vec<f32> = input.iter().map(|x| x / 32768.0).collect();
+        let mut state = DenoiseState::new();
+        state.set_sample_scale(SampleScale::Normalized);
+        let output = denoise(&mut state, &normalized);
+        for (x, y) in expected.iter().zip(&output) {
+            assert!((x / 32768.0 - y).abs() < 1e-6);
+        }
+
+        // The i16 version should give the same output, but rounded.
+        let input_i16: Vec<i16> = input.iter().map(|&x| x as i16).collect();
+        let mut output = vec![0; input.len()];
+        let mut state = DenoiseState::new();
+        state.set_sample_scale(SampleScale::Normalized);
+        for (out, chunk) in output
+            .chunks_exact_mut(FRAME_SIZE)
+            .zip(input_i16.chunks_exact(FRAME_SIZE))
+        {
+            state.process_frame_i16(out, chunk);
+        }
+        for (x, &y) in expected.iter().zip(&output) {
+            assert!((x - y as f32).abs() <= 0.5);
+        }
+    }
+
+    #[test]
+    fn try_process_frame() {
+        let input = reference_input();
+        let mut state = DenoiseState::new();
+        let mut out = [0.0; FRAME_SIZE];
+        assert_eq!(
+            state.try_process_frame(&mut out, &input[..100]),
+            Err(Error::FrameLength {
+                expected: FRAME_SIZE,
+                actual: 100
+            })
+        );
+        assert_eq!(
+            state.try_process_frame(&mut out[..100], &input[..FRAME_SIZE]),
+            Err(Error::FrameLength {
+                expected: FRAME_SIZE,
+                actual: 100
+            })
+        );
+
+        let mut bad = input[..FRAME_SIZE].to_vec();
+        bad[17] = f32::NAN;
+        assert_eq!(
+            state.try_process_frame(&mut out, &bad),
+            Err(Error::NonFinite { index: 17 })
+        );
+        bad[17] = f32::INFINITY;
+        assert_eq!(
+            state.try_process_frame(&mut out, &bad),
+            Err(Error::NonFinite { index: 17 })
+        );
+
+        // The errors shouldn't have affected the state.
+        let expected = denoise(&mut DenoiseState::new(), &input);
+        for (out, chunk) in expected
+            .chunks_exact(FRAME_SIZE)
+            .zip(input.chunks_exact(FRAME_SIZE))
+        {
+            let mut buf = [0.0; FRAME_SIZE];
+            state.try_process_frame(&mut buf, chunk).unwrap();
+            assert_eq!(&buf[..], out);
+        }
+    }
+
+    #[test]
+    fn crossfade() {
+        let input = reference_input();
+        let frames: Vec<_> = input.chunks_exact(FRAME_SIZE).collect();
+        let (before, after) = frames.split_at(frames.len() / 2);
+        let model = RnnModel::default();
+
+        // Three denoisers that start the same way: one keeps its model, one switches to a new
+        // copy of it immediately, and one crossfades to a new copy of it.
+        let mut out = [0.0; FRAME_SIZE];
+        let mut keep = DenoiseState::new();
+        for chunk in before {
+            keep.process_frame(&mut out, chunk);
+        }
+        let mut switch = keep.clone();
+        let mut fade = keep.clone();
+        switch.set_model(&model);
+        fade.crossfade_to_model(model.clone(), 10);
+
+        // Since the input history is kept, the only difference between the models is their
+        // internal state, and the crossfade interpolates the outputs of the other two.
+        let mut faded = 0;
+        for chunk in after {
+            let keep_vad = keep.process_frame(&mut out, chunk);
+            let switch_vad = switch.process_frame(&mut out, chunk);
+            let info = fade.process_frame_with_info(&mut out, chunk);
+            if !info.silence && faded < 10 {
+                faded += 1;
+                let t = faded as f32 / 10.0;
+                let expected = t * switch_vad + (1.0 - t) * keep_vad;
+                assert!((info.vad - expected).abs() < 1e-6);
+            } else {
+                assert_eq!(info.vad, switch_vad);
+            }
+        }
+        assert_eq!(faded, 10);
+        assert!(fade.fade.is_none());
+    }
+
+    #[test]
+    fn shared_model() {
+        fn assert_static<T: 'static>(_: &T) {}
+
+        let input = reference_input();
+        let model = Arc::new(RnnModel::default());
+        let mut shared = DenoiseState::from_shared_model(Arc::clone(&model));
+        let mut owned = DenoiseState::new();
+        assert_static(&shared);
+
+        let mut out_shared = [0.0; FRAME_SIZE];
+        let mut out_owned = [0.0; FRAME_SIZE];
+        for chunk in input.chunks_exact(FRAME_SIZE) {
+            shared.process_frame(&mut out_shared, chunk);
+            owned.process_frame(&mut out_owned, chunk);
+            assert_eq!(out_shared, out_owned);
+        }
+
+        // Clones share the model instead of copying it.
+        let clone = shared.clone();
+        assert_eq!(Arc::strong_count(&model), 3);
+        assert!(std::ptr::eq(clone.model(), &*model));
+        drop(clone);
+        drop(shared);
+        assert_eq!(Arc::strong_count(&model), 1);
+    }
+
+    /// Processes all the complete frames in `input` with `state`, returning the output.
+    fn process_all(state: &mut DenoiseState, input: &[f32]) -> Vec<f32> {
+        let frame_size = state.frame_size();
+        let mut out = vec![0.0; input.len() / frame_size * frame_size];
+        for (out, chunk) in out
+            .chunks_exact_mut(frame_size)
+            .zip(input.chunks_exact(frame_size))
+        {
+            state.process_frame(out, chunk);
+        }
+        out
+    }
+
+    #[test]
+    fn reset() {
+        let input = reference_input();
+        let expected = process_all(&mut DenoiseState::new(), &input);
+
+        let mut state = DenoiseState::new();
+        state.set_max_attenuation(20.0);
+        process_all(&mut state, &input[..(10 * FRAME_SIZE)]);
+        state.reset();
+        state.set_max_attenuation(f32::INFINITY);
+        assert_eq!(process_all(&mut state, &input), expected);
+    }
+
+    #[test]
+    fn snapshot() {
+        let input = reference_input();
+        let (before, after) = input.split_at(input.len() / 2);
+
+        for &rate in DenoiseState::SAMPLE_RATES {
+            let mut state = DenoiseState::new();
+            state.set_sample_rate(rate);
+            process_all(&mut state, before);
+            let snapshot = state.snapshot();
+            let expected = process_all(&mut state, after);
+
+            // Restoring the snapshot, into the same state or a new one, resumes processing from
+            // where it was taken.
+            state.restore(&snapshot).unwrap();
+            assert_eq!(process_all(&mut state, after), expected);
+            let mut other = DenoiseState::new();
+            other.set_sample_rate(rate);
+            other.restore(&snapshot).unwrap();
+            assert_eq!(process_all(&mut other, after), expected);
+
+            // A snapshot doesn't fit a state with a different sample rate.
+            let mut other = DenoiseState::new();
+            other.set_sample_rate(if rate == 48_000 { 16_000 } else { 48_000 });
+            let fresh = other.snapshot();
+            assert_eq!(other.restore(&snapshot), Err(Error::InvalidSnapshot));
+            assert_eq!(other.snapshot(), fresh);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_snapshot() {
+        let mut state = DenoiseState::new();
+        process_all(&mut state, &reference_input());
+
+        let snapshot = state.snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let read: DenoiseSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(read, snapshot);
+    }
 }
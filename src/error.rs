@@ -0,0 +1,341 @@
+//! The error type for this crate.
+
+use alloc::string::String;
+use core::fmt;
+
+/// The things that can go wrong when denoising.
+///
+/// This is returned by the `try_` methods (like [`DenoiseState::try_process_frame`]), which
+/// check their arguments instead of panicking.
+///
+/// [`DenoiseState::try_process_frame`]: crate::DenoiseState::try_process_frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// A buffer had the wrong number of samples.
+    FrameLength {
+        /// The number of samples that we expected.
+        expected: usize,
+        /// The number of samples that we got.
+        actual: usize,
+    },
+    /// The input contained a NaN or an infinite sample.
+    NonFinite {
+        /// The index of the first offending sample.
+        index: usize,
+    },
+    /// The model data was invalid.
+    InvalidModel(ModelError),
+    /// A [`DenoiseSnapshot`](crate::DenoiseSnapshot) didn't fit the denoiser that it was being
+    /// restored into, because the denoiser has a different model or sample rate (or because the
+    /// snapshot was corrupted).
+    InvalidSnapshot,
+    /// A model couldn't be written in the requested model format. The string says why (for
+    /// example, because the model has layers that are too large for the format).
+    ModelFormat(&'static str),
+}
+
+/// The things that can go wrong when reading a model with [`RnnModel::from_bytes`] or
+/// [`RnnModel::from_rnnoise_text`].
+///
+/// The `layer` fields name the layer in which the problem was found (for example,
+/// `"noise_gru"`, or `"graph"` for problems in a model graph that aren't in any particular
+/// layer), and the `offset` fields give the position in the model data, in bytes.
+///
+/// [`RnnModel::from_bytes`]: crate::RnnModel::from_bytes
+/// [`RnnModel::from_rnnoise_text`]: crate::RnnModel::from_rnnoise_text
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ModelError {
+    /// The data ended in the middle of a layer.
+    Truncated {
+        /// The layer that was being read.
+        layer: String,
+        /// The offset at which we ran out of data.
+        offset: usize,
+    },
+    /// There was some unexpected data after the last layer.
+    TrailingBytes {
+        /// The offset of the first unexpected byte.
+        offset: usize,
+        /// The number of unexpected bytes.
+        len: usize,
+    },
+    /// A layer had a negative or zero number of inputs or neurons, or so many that the number
+    /// of weights doesn't fit in a `usize`.
+    InvalidDimension {
+        /// The layer with the invalid size.
+        layer: String,
+        /// The offset of the invalid size.
+        offset: usize,
+        /// The invalid size.
+        value: i32,
+    },
+    /// A layer had an unknown activation function.
+    InvalidActivation {
+        /// The layer with the invalid activation function.
+        layer: String,
+        /// The offset of the invalid activation function.
+        offset: usize,
+        /// The invalid activation function.
+        value: i32,
+    },
+    /// A layer had an unknown weight precision.
+    InvalidPrecision {
+        /// The layer with the invalid precision.
+        layer: String,
+        /// The offset of the invalid precision.
+        offset: usize,
+        /// The invalid precision.
+        value: i32,
+    },
+    /// A layer had a weight or a scale that was NaN or infinite.
+    NonFiniteWeight {
+        /// The layer with the invalid weight.
+        layer: String,
+        /// The offset of the invalid weight.
+        offset: usize,
+    },
+    /// The size of a layer didn't fit with the rest of the model.
+    DimensionMismatch {
+        /// The size that didn't fit, for example `"noise_gru.nb_inputs"`.
+        field: String,
+        /// The offset of the size that didn't fit.
+        offset: usize,
+        /// The size that we expected, based on the rest of the model.
+        expected: usize,
+        /// The size in the model data.
+        actual: usize,
+    },
+    /// The model file has a version number that we don't know how to read.
+    UnsupportedVersion {
+        /// The version number in the file.
+        version: u16,
+    },
+    /// The model file has an architecture that we don't support.
+    UnsupportedArchitecture {
+        /// The name of the architecture in the file.
+        name: String,
+    },
+    /// The model file's checksum didn't match its contents.
+    ChecksumMismatch {
+        /// The checksum in the file.
+        expected: u32,
+        /// The checksum of the file's contents.
+        actual: u32,
+    },
+    /// A layer in a model graph had an unknown kind.
+    InvalidLayerKind {
+        /// The layer with the invalid kind.
+        layer: String,
+        /// The offset of the invalid kind.
+        offset: usize,
+        /// The invalid kind.
+        value: i32,
+    },
+    /// A model graph had a layer name that wasn't valid UTF-8.
+    InvalidLayerName {
+        /// The offset of the invalid name.
+        offset: usize,
+    },
+    /// A model graph had two layers with the same name (or a layer named `"features"`, which is
+    /// reserved for the input features).
+    DuplicateLayer {
+        /// The duplicated name.
+        name: String,
+        /// The offset of the second layer with this name.
+        offset: usize,
+    },
+    /// A model graph referred to a layer that doesn't exist. Layers can only take their input
+    /// from the layers before them, so this also happens if a layer refers to a later one.
+    UnknownLayer {
+        /// The name of the unknown layer.
+        name: String,
+        /// The offset of the reference to the unknown layer.
+        offset: usize,
+    },
+    /// The model file's metadata was invalid.
+    InvalidMetadata {
+        /// The offset of the invalid metadata.
+        offset: usize,
+    },
+    /// The first line of a text model didn't identify a supported format.
+    InvalidTextHeader,
+    /// A text model contained something that wasn't a valid number. (Weights must be between
+    /// -128 and 127.)
+    InvalidTextNumber {
+        /// The line number (starting from one) of the invalid number.
+        line: usize,
+        /// The invalid number.
+        word: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::FrameLength { expected, actual } => write!(
+                f,
+                "wrong frame length: expected {} samples, got {}",
+                expected, actual
+            ),
+            Error::NonFinite { index } => write!(f, "non-finite input sample at index {}", index),
+            Error::InvalidModel(e) => write!(f, "invalid model: {}", e),
+            Error::InvalidSnapshot => {
+                write!(
+                    f,
+                    "snapshot doesn't match the denoiser's model or sample rate"
+                )
+            }
+            Error::ModelFormat(reason) => write!(f, "can't write model: {}", reason),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InvalidModel(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ModelError> for Error {
+    fn from(e: ModelError) -> Error {
+        Error::InvalidModel(e)
+    }
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Truncated { layer, offset } => write!(
+                f,
+                "model data ended unexpectedly in layer {} (at byte {})",
+                layer, offset
+            ),
+            ModelError::TrailingBytes { offset, len } => write!(
+                f,
+                "model data has {} unexpected bytes after the last layer (at byte {})",
+                len, offset
+            ),
+            ModelError::InvalidDimension {
+                layer,
+                offset,
+                value,
+            } => write!(
+                f,
+                "layer {} has invalid size {} (at byte {})",
+                layer, value, offset
+            ),
+            ModelError::InvalidActivation {
+                layer,
+                offset,
+                value,
+            } => write!(
+                f,
+                "layer {} has unknown activation function {} (at byte {})",
+                layer, value, offset
+            ),
+            ModelError::InvalidPrecision {
+                layer,
+                offset,
+                value,
+            } => write!(
+                f,
+                "layer {} has unknown weight precision {} (at byte {})",
+                layer, value, offset
+            ),
+            ModelError::NonFiniteWeight { layer, offset } => write!(
+                f,
+                "layer {} has a non-finite weight (at byte {})",
+                layer, offset
+            ),
+            ModelError::DimensionMismatch {
+                field,
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} should be {}, but it is {} (at byte {})",
+                field, expected, actual, offset
+            ),
+            ModelError::UnsupportedVersion { version } => {
+                write!(f, "unsupported model file version {}", version)
+            }
+            ModelError::UnsupportedArchitecture { name } => {
+                write!(f, "unsupported model architecture {:?}", name)
+            }
+            ModelError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "model file is corrupted (checksum is {:08x}, expected {:08x})",
+                actual, expected
+            ),
+            ModelError::InvalidLayerKind {
+                layer,
+                offset,
+                value,
+            } => write!(
+                f,
+                "layer {} has unknown kind {} (at byte {})",
+                layer, value, offset
+            ),
+            ModelError::InvalidLayerName { offset } => {
+                write!(f, "invalid layer name (at byte {})", offset)
+            }
+            ModelError::DuplicateLayer { name, offset } => {
+                write!(f, "duplicate layer name {:?} (at byte {})", name, offset)
+            }
+            ModelError::UnknownLayer { name, offset } => {
+                write!(f, "unknown layer {:?} (at byte {})", name, offset)
+            }
+            ModelError::InvalidMetadata { offset } => {
+                write!(f, "invalid model metadata (at byte {})", offset)
+            }
+            ModelError::InvalidTextHeader => {
+                write!(
+                    f,
+                    "unknown model format (expected an rnnoise-nu text model)"
+                )
+            }
+            ModelError::InvalidTextNumber { line, word } => {
+                write!(f, "invalid number {:?} on line {}", word, line)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ModelError {}
+
+/// Returns an error if any of the samples are NaN or infinite.
+pub(crate) fn check_finite(input: &[f32]) -> Result<(), Error> {
+    match input.iter().position(|x| !x.is_finite()) {
+        Some(index) => Err(Error::NonFinite { index }),
+        None => Ok(()),
+    }
+}
+
+/// Copies a saved buffer from a snapshot, returning an error if it has the wrong length.
+pub(crate) fn restore_buf(dst: &mut [f32], src: &[f32]) -> Result<(), Error> {
+    if dst.len() != src.len() {
+        return Err(Error::InvalidSnapshot);
+    }
+    dst.copy_from_slice(src);
+    Ok(())
+}
+
+/// Returns an error if `buf` doesn't have length `expected`.
+pub(crate) fn check_len<T>(buf: &[T], expected: usize) -> Result<(), Error> {
+    if buf.len() == expected {
+        Ok(())
+    } else {
+        Err(Error::FrameLength {
+            expected,
+            actual: buf.len(),
+        })
+    }
+}
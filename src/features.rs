@@ -5,11 +5,30 @@
 //! detection, or when the `train` feature is enabled they can be collected and used to train new
 //! neural nets.
 
+use alloc::{vec, vec::Vec};
+
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
+
+use crate::error::restore_buf;
 use crate::{
-    common, Complex, CEPS_MEM, FRAME_SIZE, FREQ_SIZE, NB_BANDS, NB_DELTA_CEPS, NB_FEATURES,
-    PITCH_BUF_SIZE, WINDOW_SIZE,
+    common, Complex, Error, CEPS_MEM, FRAME_SIZE, FREQ_SIZE, NB_BANDS, NB_DELTA_CEPS, NB_FEATURES,
+    PITCH_BUF_SIZE, PITCH_MAX_PERIOD, WINDOW_SIZE,
 };
-use easyfft::prelude::*;
+
+/// The parts of a [`DenoiseFeatures`] that are carried over from one frame to the next, as saved
+/// in a [`DenoiseSnapshot`](crate::DenoiseSnapshot).
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct FeaturesSnapshot {
+    input_mem: Vec<f32>,
+    cepstral_mem: Vec<Vec<f32>>,
+    mem_id: usize,
+    mem_hp_x: [f32; 2],
+    synthesis_mem: Vec<f32>,
+    last_period: usize,
+    last_gain: f32,
+}
 
 /// Contains the necessary state to compute the features of audio input and synthesize the output.
 ///
@@ -27,12 +46,15 @@ pub struct DenoiseFeatures {
     mem_hp_x: [f32; 2],
     synthesis_mem: [f32; FRAME_SIZE],
     window_buf: [f32; WINDOW_SIZE],
+    /// Scratch space for the Fourier transforms.
+    fft_scratch: Vec<Complex>,
 
     // What follows are various buffers. The names are cryptic, but they follow a pattern.
-    /// The Fourier transform of the most recent frame of input.
-    pub x: DynRealDft<f32>,
+    /// The Fourier transform of the most recent frame of input, from the zero frequency up to the
+    /// Nyquist frequency.
+    pub x: [Complex; FREQ_SIZE],
     /// The Fourier transform of a pitch-period-shifted window of input.
-    pub p: DynRealDft<f32>,
+    pub p: [Complex; FREQ_SIZE],
     /// The band energies of `x` (the signal).
     pub ex: [f32; NB_BANDS],
     /// The band energies of `p` (the signal, lagged by one pitch period).
@@ -41,6 +63,8 @@ pub struct DenoiseFeatures {
     pub exp: [f32; NB_BANDS],
     /// The computed features.
     features: [f32; NB_FEATURES],
+    /// The pitch period of the most recent frame.
+    pitch_idx: usize,
 
     pitch_finder: crate::pitch::PitchFinder,
 }
@@ -63,21 +87,84 @@ impl DenoiseFeatures {
             mem_hp_x: [0.0; 2],
             synthesis_mem: [0.0; FRAME_SIZE],
             window_buf: [0.0; WINDOW_SIZE],
-            x: DynRealDft::new(0.0, &[Complex::default(); FREQ_SIZE - 1], WINDOW_SIZE),
-            p: DynRealDft::new(0.0, &[Complex::default(); FREQ_SIZE - 1], WINDOW_SIZE),
+            fft_scratch: vec![Complex::default(); common().fft.scratch_len()],
+            x: [Complex::default(); FREQ_SIZE],
+            p: [Complex::default(); FREQ_SIZE],
             ex: [0.0; NB_BANDS],
             ep: [0.0; NB_BANDS],
             exp: [0.0; NB_BANDS],
             features: [0.0; NB_FEATURES],
+            pitch_idx: 0,
             pitch_finder: crate::pitch::PitchFinder::new(),
         }
     }
 
+    /// Forgets the history of the signal, leaving this in the same state as a new
+    /// `DenoiseFeatures` (but without allocating).
+    pub(crate) fn reset(&mut self) {
+        self.input_mem = [0.0; max(FRAME_SIZE, PITCH_BUF_SIZE)];
+        self.cepstral_mem = [[0.0; NB_BANDS]; CEPS_MEM];
+        self.mem_id = 0;
+        self.mem_hp_x = [0.0; 2];
+        self.synthesis_mem = [0.0; FRAME_SIZE];
+        self.window_buf = [0.0; WINDOW_SIZE];
+        self.x = [Complex::default(); FREQ_SIZE];
+        self.p = [Complex::default(); FREQ_SIZE];
+        self.ex = [0.0; NB_BANDS];
+        self.ep = [0.0; NB_BANDS];
+        self.exp = [0.0; NB_BANDS];
+        self.features = [0.0; NB_FEATURES];
+        self.pitch_idx = 0;
+        self.pitch_finder.set_last_pitch(0, 0.0);
+    }
+
+    /// Saves the parts of the state that are carried over to the next frame.
+    pub(crate) fn snapshot(&self) -> FeaturesSnapshot {
+        let (last_period, last_gain) = self.pitch_finder.last_pitch();
+        FeaturesSnapshot {
+            input_mem: self.input_mem.to_vec(),
+            cepstral_mem: self.cepstral_mem.iter().map(|c| c.to_vec()).collect(),
+            mem_id: self.mem_id,
+            mem_hp_x: self.mem_hp_x,
+            synthesis_mem: self.synthesis_mem.to_vec(),
+            last_period,
+            last_gain,
+        }
+    }
+
+    /// Restores the state saved by [`DenoiseFeatures::snapshot`].
+    ///
+    /// If the snapshot is invalid, this returns an error and leaves `self` in an unspecified (but
+    /// safe to use) state.
+    pub(crate) fn restore(&mut self, snapshot: &FeaturesSnapshot) -> Result<(), Error> {
+        if snapshot.mem_id >= CEPS_MEM
+            || snapshot.cepstral_mem.len() != CEPS_MEM
+            || snapshot.last_period > PITCH_MAX_PERIOD
+        {
+            return Err(Error::InvalidSnapshot);
+        }
+        restore_buf(&mut self.input_mem, &snapshot.input_mem)?;
+        for (dst, src) in self.cepstral_mem.iter_mut().zip(&snapshot.cepstral_mem) {
+            restore_buf(dst, src)?;
+        }
+        restore_buf(&mut self.synthesis_mem, &snapshot.synthesis_mem)?;
+        self.mem_id = snapshot.mem_id;
+        self.mem_hp_x = snapshot.mem_hp_x;
+        self.pitch_finder
+            .set_last_pitch(snapshot.last_period, snapshot.last_gain);
+        Ok(())
+    }
+
     /// Returns the computed features.
     pub fn features(&self) -> &[f32] {
         &self.features[..]
     }
 
+    /// Returns the pitch period (in samples) that was detected in the most recent frame.
+    pub fn pitch_period(&self) -> usize {
+        self.pitch_idx
+    }
+
     /// Shifts our input buffer and adds the new input to it. This is mainly used when generating
     /// training data: when running the noise reduction we use [`DenoiseFeatures::shift_and_filter_input`]
     /// instead.
@@ -120,15 +207,18 @@ impl DenoiseFeatures {
             &self.input_mem,
             0,
             &mut self.window_buf,
+            &mut self.fft_scratch,
             &mut self.x,
             &mut self.ex,
         );
         let pitch_idx = self.find_pitch();
+        self.pitch_idx = pitch_idx;
 
         transform_input(
             &self.input_mem,
             pitch_idx,
             &mut self.window_buf,
+            &mut self.fft_scratch,
             &mut self.p,
             &mut self.ep,
         );
@@ -235,15 +325,7 @@ impl DenoiseFeatures {
             r[i] *= (self.ex[i] / (1e-8 + self.ep[i])).sqrt();
         }
         crate::interp_band_gain(&mut rf[..], &r[..]);
-        let rf: &mut [f32] = &mut rf;
-        *self.x.get_offset_mut() += self.p.get_offset() * rf[0];
-        for ((x, p), rf) in self
-            .x
-            .get_frequency_bins_mut()
-            .iter_mut()
-            .zip(self.p.get_frequency_bins())
-            .zip(rf[1..].iter())
-        {
+        for ((x, p), rf) in self.x.iter_mut().zip(&self.p).zip(&rf) {
             *x += p * rf;
         }
 
@@ -253,15 +335,20 @@ impl DenoiseFeatures {
             r[i] = (self.ex[i] / (1e-8 + new_e[i])).sqrt();
         }
         crate::interp_band_gain(&mut rf[..], &r[..]);
-        self.x *= &*rf;
+        self.apply_gain(&rf);
     }
 
     pub(crate) fn apply_gain(&mut self, gain: &[f32; FREQ_SIZE]) {
-        self.x *= gain as &[f32];
+        for (x, g) in self.x.iter_mut().zip(gain) {
+            *x *= g;
+        }
     }
 
     pub(crate) fn frame_synthesis(&mut self, out: &mut [f32]) {
-        self.x.real_ifft_using(&mut self.window_buf);
+        let mut spectrum = self.x;
+        common()
+            .fft
+            .inverse(&mut spectrum, &mut self.window_buf, &mut self.fft_scratch);
         // Not too sure why this scaling factor is introduced
         for x in &mut self.window_buf {
             *x /= 2.0;
@@ -282,17 +369,20 @@ fn transform_input(
     input: &[f32],
     lag: usize,
     window_buf: &mut [f32; WINDOW_SIZE],
-    x: &mut DynRealDft<f32>,
+    fft_scratch: &mut [Complex],
+    x: &mut [Complex; FREQ_SIZE],
     ex: &mut [f32],
 ) {
     let input = &input[input.len().checked_sub(WINDOW_SIZE + lag).unwrap()..];
     crate::apply_window(&mut window_buf[..], input);
-    window_buf.real_fft_using(x);
+    common().fft.forward(window_buf, x, fft_scratch);
 
     // In the original RNNoise code, the forward transform is normalized and the inverse
-    // tranform isn't. `rustfft` doesn't normalize either one, so we do it ourselves.
+    // tranform isn't. Our transforms don't normalize either one, so we do it ourselves.
     let norm = common().wnorm;
-    *x *= norm;
+    for x in x.iter_mut() {
+        *x *= norm;
+    }
 
     crate::compute_band_corr(ex, x, x);
 }
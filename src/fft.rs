@@ -0,0 +1,221 @@
+//! Fourier transforms of one window of real-valued input.
+//!
+//! With the `std` feature we use `realfft`. Without it, we use a simple mixed-radix transform of
+//! our own, since `realfft` (or rather `rustfft`) needs `std`. Either way, the transforms are
+//! planned up front and then run without allocating, given enough scratch space.
+
+#[cfg(feature = "std")]
+use alloc::sync::Arc;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
+#[cfg(feature = "std")]
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+
+use crate::{Complex, FREQ_SIZE, WINDOW_SIZE};
+
+/// The radices of our own transform. Their product must be `WINDOW_SIZE`.
+#[cfg(not(feature = "std"))]
+const RADICES: [usize; 5] = [4, 4, 4, 3, 5];
+
+/// Forward and inverse Fourier transforms of size `WINDOW_SIZE`. Neither one is normalized.
+pub(crate) struct Fft {
+    #[cfg(feature = "std")]
+    forward: Arc<dyn RealToComplex<f32>>,
+    #[cfg(feature = "std")]
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    /// `twiddles[k]` is `exp(-2πik / WINDOW_SIZE)`.
+    #[cfg(not(feature = "std"))]
+    twiddles: Vec<Complex>,
+}
+
+impl Fft {
+    #[cfg(feature = "std")]
+    pub(crate) fn new() -> Fft {
+        let mut planner = RealFftPlanner::new();
+        Fft {
+            forward: planner.plan_fft_forward(WINDOW_SIZE),
+            inverse: planner.plan_fft_inverse(WINDOW_SIZE),
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn new() -> Fft {
+        debug_assert_eq!(RADICES.iter().product::<usize>(), WINDOW_SIZE);
+        let twiddles = (0..WINDOW_SIZE)
+            .map(|k| {
+                let phase = -2.0 * core::f64::consts::PI * k as f64 / WINDOW_SIZE as f64;
+                Complex::new(phase.cos() as f32, phase.sin() as f32)
+            })
+            .collect();
+        Fft { twiddles }
+    }
+
+    /// The length of the scratch space needed by [`Fft::forward`] and [`Fft::inverse`].
+    #[cfg(feature = "std")]
+    pub(crate) fn scratch_len(&self) -> usize {
+        self.forward
+            .get_scratch_len()
+            .max(self.inverse.get_scratch_len())
+    }
+
+    /// The length of the scratch space needed by [`Fft::forward`] and [`Fft::inverse`].
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn scratch_len(&self) -> usize {
+        2 * WINDOW_SIZE
+    }
+
+    /// Transforms `input`, putting the non-negative frequencies in `output`. The contents of
+    /// `input` are destroyed.
+    pub(crate) fn forward(
+        &self,
+        input: &mut [f32; WINDOW_SIZE],
+        output: &mut [Complex; FREQ_SIZE],
+        scratch: &mut [Complex],
+    ) {
+        #[cfg(feature = "std")]
+        self.forward
+            .process_with_scratch(input, output, scratch)
+            .unwrap();
+
+        #[cfg(not(feature = "std"))]
+        {
+            let (buf, tmp) = scratch.split_at_mut(WINDOW_SIZE);
+            for (b, &x) in buf.iter_mut().zip(input.iter()) {
+                *b = Complex::new(x, 0.0);
+            }
+            self.transform(buf, tmp);
+            output.copy_from_slice(&buf[..FREQ_SIZE]);
+        }
+
+        // These are real for real input, but rounding errors might say otherwise.
+        output[0].im = 0.0;
+        output[FREQ_SIZE - 1].im = 0.0;
+    }
+
+    /// Transforms the non-negative frequencies in `input` back into a real signal. The
+    /// imaginary parts of the first and last elements of `input` are ignored, and the contents
+    /// of `input` are destroyed.
+    pub(crate) fn inverse(
+        &self,
+        input: &mut [Complex; FREQ_SIZE],
+        output: &mut [f32; WINDOW_SIZE],
+        scratch: &mut [Complex],
+    ) {
+        input[0].im = 0.0;
+        input[FREQ_SIZE - 1].im = 0.0;
+
+        #[cfg(feature = "std")]
+        self.inverse
+            .process_with_scratch(input, output, scratch)
+            .unwrap();
+
+        // The inverse transform is the conjugate of the forward transform of the conjugate. For
+        // the negative frequencies, the conjugate of the conjugate is just the original.
+        #[cfg(not(feature = "std"))]
+        {
+            let (buf, tmp) = scratch.split_at_mut(WINDOW_SIZE);
+            for (b, x) in buf.iter_mut().zip(input.iter()) {
+                *b = x.conj();
+            }
+            for (b, x) in buf[FREQ_SIZE..]
+                .iter_mut()
+                .zip(input[1..(FREQ_SIZE - 1)].iter().rev())
+            {
+                *b = *x;
+            }
+            self.transform(buf, tmp);
+            for (o, b) in output.iter_mut().zip(buf.iter()) {
+                *o = b.re;
+            }
+        }
+    }
+
+    /// Transforms `buf` in place, using `tmp` as scratch space.
+    ///
+    /// This is the Stockham algorithm, which at each step splits the signal into `radix`
+    /// interleaved sub-signals and does a small DFT across them, with the results ending up in
+    /// the right order without any bit-reversal.
+    #[cfg(not(feature = "std"))]
+    fn transform(&self, buf: &mut [Complex], tmp: &mut [Complex]) {
+        let mut x = buf;
+        let mut y = tmp;
+        // `n` is the length of the sub-signals at this step, and `stride` is their number.
+        let mut n = WINDOW_SIZE;
+        let mut stride = 1;
+        let mut sums = [Complex::default(); 5];
+        for &radix in &RADICES {
+            let m = n / radix;
+            for p in 0..m {
+                for q in 0..stride {
+                    for (j, sum) in sums[..radix].iter_mut().enumerate() {
+                        *sum = Complex::default();
+                        for k in 0..radix {
+                            let w = self.twiddles[(j * k * (WINDOW_SIZE / radix)) % WINDOW_SIZE];
+                            *sum += x[q + stride * (p + k * m)] * w;
+                        }
+                    }
+                    for (j, sum) in sums[..radix].iter().enumerate() {
+                        y[q + stride * (radix * p + j)] = sum * self.twiddles[j * p * stride];
+                    }
+                }
+            }
+            core::mem::swap(&mut x, &mut y);
+            n = m;
+            stride *= radix;
+        }
+        // After an odd number of steps, the result is in `tmp`.
+        if RADICES.len() % 2 == 1 {
+            y.copy_from_slice(x);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Computes the DFT of `input` directly from the definition.
+    fn dft(input: &[f32]) -> Vec<Complex> {
+        let n = input.len();
+        (0..FREQ_SIZE)
+            .map(|k| {
+                input
+                    .iter()
+                    .enumerate()
+                    .map(|(t, &x)| {
+                        let phase = -2.0 * std::f64::consts::PI * ((k * t) % n) as f64 / n as f64;
+                        let w = num_complex::Complex64::new(phase.cos(), phase.sin());
+                        w * x as f64
+                    })
+                    .sum::<num_complex::Complex64>()
+            })
+            .map(|x| Complex::new(x.re as f32, x.im as f32))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let fft = Fft::new();
+        let mut scratch = vec![Complex::default(); fft.scratch_len()];
+        let signal: Vec<f32> = (0..WINDOW_SIZE)
+            .map(|i| ((i * 7919) % 1000) as f32 / 500.0 - 1.0)
+            .collect();
+
+        let mut input = [0.0; WINDOW_SIZE];
+        input.copy_from_slice(&signal);
+        let mut spectrum = [Complex::default(); FREQ_SIZE];
+        fft.forward(&mut input, &mut spectrum, &mut scratch);
+        for (x, y) in spectrum.iter().zip(dft(&signal)) {
+            assert!((x - y).norm_sqr() < 1e-6, "{} != {}", x, y);
+        }
+
+        let mut output = [0.0; WINDOW_SIZE];
+        fft.inverse(&mut spectrum, &mut output, &mut scratch);
+        for (x, y) in output.iter().zip(&signal) {
+            assert!((x / WINDOW_SIZE as f32 - y).abs() < 1e-5, "{} != {}", x, y);
+        }
+    }
+}
@@ -1,11 +1,29 @@
 #![deny(missing_docs)]
+// Tests always get `std`, for convenience.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
 //! `nnnoiseless` is a crate for removing noise from audio. The main entry point is
 //! [`DenoiseState`].
 //!
+//! # Features
+//!
+//! The `std` feature, which is enabled by default, is needed for the command-line tool, the C API,
+//! [`DenoiseSignal`] and the parts of the API that do I/O (such as [`RnnModel::write_to`]).
+//! Without it, this crate only needs `alloc`, so it can be used on embedded targets.
+//!
 //! [`DenoiseState`]: struct.DenoiseState.html
+//! [`DenoiseSignal`]: struct.DenoiseSignal.html
+//! [`RnnModel::write_to`]: struct.RnnModel.html#method.write_to
+
+extern crate alloc;
 
-use once_cell::sync::OnceCell;
+use alloc::boxed::Box;
+
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
+use once_cell::race::OnceBox;
+
+use crate::fft::Fft;
 
 #[cfg(any(cargo_c, feature = "capi"))]
 mod capi;
@@ -16,21 +34,31 @@ pub mod util;
 #[cfg(not(feature = "train"))]
 mod util;
 
-#[cfg(feature = "dasp")]
+#[cfg(all(feature = "dasp", feature = "std"))]
 mod signal;
-#[cfg(feature = "dasp")]
+#[cfg(all(feature = "dasp", feature = "std"))]
 pub use dasp;
 
+mod container;
 mod denoise;
+mod error;
 mod features;
+mod fft;
+mod model_reader;
 mod pitch;
+mod resample;
 mod rnn;
+mod simd;
+mod stream;
 
-pub use denoise::DenoiseState;
+pub use container::ModelMetadata;
+pub use denoise::{DenoiseSnapshot, DenoiseState, FrameInfo, SampleScale};
+pub use error::{Error, ModelError};
 pub use features::DenoiseFeatures;
-pub use rnn::RnnModel;
-#[cfg(feature = "dasp")]
+pub use rnn::{Activation, LayerInfo, LayerKind, ModelRef, RnnModel, WeightPrecision, WeightStats};
+#[cfg(all(feature = "dasp", feature = "std"))]
 pub use signal::DenoiseSignal;
+pub use stream::StreamingDenoiser;
 
 #[doc(hidden)]
 pub const FRAME_SIZE_SHIFT: usize = 2;
@@ -56,7 +84,7 @@ pub const EBAND_5MS: [usize; 22] = [
     // 0  200 400 600 800  1k 1.2 1.4 1.6  2k 2.4 2.8 3.2  4k 4.8 5.6 6.8  8k 9.6 12k 15.6 20k*/
     0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 12, 14, 16, 20, 24, 28, 34, 40, 48, 60, 78, 100,
 ];
-type Complex = easyfft::num_complex::Complex32;
+type Complex = num_complex::Complex32;
 
 /// Computes the correlation between two frequency-domain signals, and aggregates the correlation
 /// into bands.
@@ -100,13 +128,16 @@ struct CommonState {
     window: [f32; WINDOW_SIZE],
     dct_table: [f32; NB_BANDS * NB_BANDS],
     wnorm: f32,
+    fft: Fft,
 }
 
-static COMMON: OnceCell<CommonState> = OnceCell::new();
+// If several threads race to initialize this, they all do the work but only one of them wins.
+// That's fine, and unlike a lock it works without `std`.
+static COMMON: OnceBox<CommonState> = OnceBox::new();
 
 fn common() -> &'static CommonState {
     if COMMON.get().is_none() {
-        let pi = std::f64::consts::PI;
+        let pi = core::f64::consts::PI;
         let mut window = [0.0; WINDOW_SIZE];
         for i in 0..FRAME_SIZE {
             let sin = (0.5 * pi * (i as f64 + 0.5) / FRAME_SIZE as f64).sin();
@@ -126,11 +157,12 @@ fn common() -> &'static CommonState {
             }
         }
 
-        let _ = COMMON.set(CommonState {
+        let _ = COMMON.set(Box::new(CommonState {
             window,
             dct_table,
             wnorm,
-        });
+            fft: Fft::new(),
+        }));
     }
     COMMON.get().unwrap()
 }
@@ -213,16 +245,20 @@ mod tests {
     }
 
     #[test]
+    #[cfg(all(feature = "dasp", feature = "std"))]
     fn compare_signal_to_reference() {
         use dasp::signal::{self, Signal};
 
         let reference_input = to_i16(include_bytes!("../test_data/testing.raw"));
         let reference_output = to_i16(include_bytes!("../test_data/reference_output.raw"));
+        let input_len = reference_input.len();
         let output: Vec<f32> = DenoiseSignal::new(signal::from_iter(reference_input.into_iter()))
             .until_exhausted()
             .map(|x| x * 32768.0)
             .collect();
 
-        compare(&output, &reference_output);
+        // The reference output is missing the final partial frame, but we don't drop it.
+        assert_eq!(output.len(), input_len);
+        compare(&output[..reference_output.len()], &reference_output);
     }
 }
@@ -0,0 +1,610 @@
+//! Reading the layers of an [`RnnModel`] from the various model file formats.
+//!
+//! All of the formats store the same things in the same order: the layers are simply
+//! concatenated, and each layer consists of a header followed by the layer's weights. They
+//! differ only in how the numbers are encoded, which is what the [`LayerReader`] trait
+//! abstracts over.
+//!
+//! The format for a dense layer is
+//! <nb_inputs> <nb_neurons> <activation> [<precision> <scale>]
+//! <weights...>
+//! <bias...>
+//! where each of the <?> terms represents a single integer, and each of the <?...> terms
+//! represents an array of integers of the appropriate length (`weights` has length
+//! `nb_neurons * nb_inputs` and `bias` has length `nb_neurons`). The `precision` and `scale`
+//! are only present in the container format; they give the numeric type of the weights and the
+//! factor by which they should be multiplied (see [`WeightPrecision`]). In the other formats,
+//! the weights are integers with a scale of `1 / 256`.
+//!
+//! The format for a GRU layer is
+//! <nb_inputs> <nb_neurons> <activation> [<precision> <scale>]
+//! <input_weights...>
+//! <recurrent_weights...>
+//! <bias...>
+//! where `input_weights` and `recurrent_weights` have length `3 * nb_inputs * nb_neurons` each,
+//! and `bias` has length `3 * nb_neurons`.
+//!
+//! In the legacy and text formats, and in the container format with the `rnnoise` architecture,
+//! the model consists of the six layers of the original RNNoise network (see `RNNOISE_LAYERS`).
+//! In the container format with the `graph` architecture, the model consists of
+//! - the number of layers, as a `u32`,
+//! - for each layer:
+//!   - the layer's name, as a string,
+//!   - the layer's kind, as a `u8` (0 for a dense layer and 1 for a GRU layer),
+//!   - the number of the layer's inputs, as a `u32`, followed by the names of the inputs, as
+//!     strings (each of which is either `features` or the name of an earlier layer),
+//!   - the layer itself, as above,
+//! - the name of the layer that outputs the band gains, as a string,
+//! - the name of the layer that outputs the voice activity probability, as a string (which is
+//!   empty if there is no such layer).
+//!
+//! Here, strings are UTF-8 with a `u32` length prefix, as in the rest of the container. The
+//! inputs of each layer are concatenated in order, and the size of the concatenation must be
+//! the layer's number of inputs.
+
+use alloc::{borrow::ToOwned, format, string::String, vec::Vec};
+
+use crate::rnn::{
+    Activation, DenseLayer, GruLayer, I8Weights, Layer, LayerKind, Node, Source, Weights, FEATURES,
+    RNNOISE_LAYERS, WEIGHTS_SCALE,
+};
+use crate::{ModelError, ModelMetadata, RnnModel, WeightPrecision};
+
+/// A source of layer data.
+pub(crate) trait LayerReader {
+    /// The offset of the next number to be read, for error messages.
+    fn offset(&self) -> usize;
+
+    /// The number of bytes (or whatever the format is made of) that remain to be read.
+    fn remaining(&self) -> usize;
+
+    /// Reads a layer size, which might be negative (and therefore invalid).
+    fn size(&mut self, layer: &str) -> Result<i32, ModelError>;
+
+    /// Reads the number of an activation function.
+    fn activation(&mut self, layer: &str) -> Result<i32, ModelError>;
+
+    /// Reads the precision and scale of a layer's weights.
+    fn precision(&mut self, layer: &str) -> Result<(WeightPrecision, f32), ModelError>;
+
+    /// Reads `len` weights of the given precision.
+    fn weights(
+        &mut self,
+        layer: &str,
+        precision: WeightPrecision,
+        len: usize,
+    ) -> Result<Weights, ModelError>;
+}
+
+/// The binary model formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BinaryFormat {
+    /// The format without a header, as written by the training scripts.
+    Legacy,
+    /// The layer data in our container format.
+    Container,
+}
+
+/// Decides how to store some `i8` weights, given the weights and their offset in the file.
+pub(crate) type StoreWeights<'a> = &'a dyn Fn(&'a [i8], usize) -> I8Weights;
+
+/// Reads layers from binary data.
+///
+/// In the legacy format, the header of each layer consists of three `i8`s, which means that
+/// layers can have at most 127 inputs and neurons, and the weights are `i8`s. In the container
+/// format, the sizes are little-endian `u16`s instead, the activation function is followed by a
+/// byte for the precision and an `f32` for the scale, and the weights are little-endian numbers
+/// of the given precision.
+pub(crate) struct BinaryReader<'a> {
+    data: &'a [i8],
+    pos: usize,
+    /// The offset of `data` in the file.
+    base_offset: usize,
+    format: BinaryFormat,
+    moo: StoreWeights<'a>,
+}
+
+impl<'a> BinaryReader<'a> {
+    pub(crate) fn new(
+        data: &'a [i8],
+        base_offset: usize,
+        format: BinaryFormat,
+        moo: StoreWeights<'a>,
+    ) -> BinaryReader<'a> {
+        BinaryReader {
+            data,
+            pos: 0,
+            base_offset,
+            format,
+            moo,
+        }
+    }
+
+    fn take(&mut self, layer: &str, len: usize) -> Result<&'a [i8], ModelError> {
+        if self.remaining() < len {
+            return Err(ModelError::Truncated {
+                layer: layer.to_owned(),
+                offset: self.offset(),
+            });
+        }
+        let ret = &self.data[self.pos..(self.pos + len)];
+        self.pos += len;
+        Ok(ret)
+    }
+
+    /// Reads `len` little-endian numbers that are `N` bytes long each.
+    fn numbers<T, const N: usize>(
+        &mut self,
+        layer: &str,
+        len: usize,
+        from_le_bytes: fn([u8; N]) -> T,
+    ) -> Result<Vec<T>, ModelError> {
+        let byte_len = len.checked_mul(N).ok_or_else(|| ModelError::Truncated {
+            layer: layer.to_owned(),
+            offset: self.offset(),
+        })?;
+        let bytes = self.take(layer, byte_len)?;
+        Ok(bytes
+            .chunks_exact(N)
+            .map(|b| {
+                let mut buf = [0; N];
+                for (x, &y) in buf.iter_mut().zip(b) {
+                    *x = y as u8;
+                }
+                from_le_bytes(buf)
+            })
+            .collect())
+    }
+
+    fn u32(&mut self, layer: &str) -> Result<usize, ModelError> {
+        Ok(self.numbers(layer, 1, u32::from_le_bytes)?[0] as usize)
+    }
+
+    fn string(&mut self, layer: &str) -> Result<String, ModelError> {
+        let len = self.u32(layer)?;
+        let offset = self.offset();
+        let bytes = self.numbers(layer, len, |[b]: [u8; 1]| b)?;
+        String::from_utf8(bytes).map_err(|_| ModelError::InvalidLayerName { offset })
+    }
+}
+
+impl<'a> LayerReader for BinaryReader<'a> {
+    fn offset(&self) -> usize {
+        self.base_offset + self.pos
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn size(&mut self, layer: &str) -> Result<i32, ModelError> {
+        match self.format {
+            BinaryFormat::Legacy => Ok(self.take(layer, 1)?[0] as i32),
+            BinaryFormat::Container => Ok(self.numbers(layer, 1, u16::from_le_bytes)?[0] as i32),
+        }
+    }
+
+    fn activation(&mut self, layer: &str) -> Result<i32, ModelError> {
+        Ok(self.take(layer, 1)?[0] as i32)
+    }
+
+    fn precision(&mut self, layer: &str) -> Result<(WeightPrecision, f32), ModelError> {
+        if self.format == BinaryFormat::Legacy {
+            return Ok((WeightPrecision::I8, WEIGHTS_SCALE));
+        }
+
+        let offset = self.offset();
+        let precision = match self.take(layer, 1)?[0] {
+            0 => WeightPrecision::I8,
+            1 => WeightPrecision::I16,
+            2 => WeightPrecision::F32,
+            value => {
+                return Err(ModelError::InvalidPrecision {
+                    layer: layer.to_owned(),
+                    offset,
+                    value: value as i32,
+                })
+            }
+        };
+        let offset = self.offset();
+        let scale = self.numbers(layer, 1, f32::from_le_bytes)?[0];
+        if !scale.is_finite() {
+            return Err(ModelError::NonFiniteWeight {
+                layer: layer.to_owned(),
+                offset,
+            });
+        }
+        Ok((precision, scale))
+    }
+
+    fn weights(
+        &mut self,
+        layer: &str,
+        precision: WeightPrecision,
+        len: usize,
+    ) -> Result<Weights, ModelError> {
+        match precision {
+            WeightPrecision::I8 => {
+                let offset = self.offset();
+                let moo = self.moo;
+                Ok(Weights::I8(moo(self.take(layer, len)?, offset)))
+            }
+            WeightPrecision::I16 => Ok(Weights::I16(self.numbers(
+                layer,
+                len,
+                i16::from_le_bytes,
+            )?)),
+            WeightPrecision::F32 => {
+                let offset = self.offset();
+                let weights = self.numbers(layer, len, f32::from_le_bytes)?;
+                if let Some(i) = weights.iter().position(|x| !x.is_finite()) {
+                    return Err(ModelError::NonFiniteWeight {
+                        layer: layer.to_owned(),
+                        offset: offset + 4 * i,
+                    });
+                }
+                Ok(Weights::F32(weights))
+            }
+        }
+    }
+}
+
+/// Reads layers from the `rnnoise-nu` text format, which is a list of whitespace-separated
+/// integers. The offsets count integers, not bytes.
+pub(crate) struct TextReader<'a> {
+    /// The remaining numbers, along with their line numbers.
+    words: Vec<(usize, &'a str)>,
+    pos: usize,
+}
+
+impl<'a> TextReader<'a> {
+    /// Creates a reader for `lines`, the first of which has line number `first_line`.
+    pub(crate) fn new(lines: core::str::Lines<'a>, first_line: usize) -> TextReader<'a> {
+        let words = lines
+            .enumerate()
+            .flat_map(|(i, line)| line.split_whitespace().map(move |w| (i + first_line, w)))
+            .collect();
+        TextReader { words, pos: 0 }
+    }
+
+    fn next_number<T: core::str::FromStr>(&mut self, layer: &str) -> Result<T, ModelError> {
+        let (line, word) = *self
+            .words
+            .get(self.pos)
+            .ok_or_else(|| ModelError::Truncated {
+                layer: layer.to_owned(),
+                offset: self.pos,
+            })?;
+        self.pos += 1;
+        word.parse().map_err(|_| ModelError::InvalidTextNumber {
+            line,
+            word: word.to_owned(),
+        })
+    }
+}
+
+impl<'a> LayerReader for TextReader<'a> {
+    fn offset(&self) -> usize {
+        self.pos
+    }
+
+    fn remaining(&self) -> usize {
+        self.words.len() - self.pos
+    }
+
+    fn size(&mut self, layer: &str) -> Result<i32, ModelError> {
+        self.next_number(layer)
+    }
+
+    fn activation(&mut self, layer: &str) -> Result<i32, ModelError> {
+        self.next_number(layer)
+    }
+
+    fn precision(&mut self, _layer: &str) -> Result<(WeightPrecision, f32), ModelError> {
+        Ok((WeightPrecision::I8, WEIGHTS_SCALE))
+    }
+
+    fn weights(
+        &mut self,
+        layer: &str,
+        precision: WeightPrecision,
+        len: usize,
+    ) -> Result<Weights, ModelError> {
+        // `precision` always comes from `TextReader::precision`.
+        debug_assert_eq!(precision, WeightPrecision::I8);
+        (0..len)
+            .map(|_| self.next_number(layer))
+            .collect::<Result<Vec<i8>, _>>()
+            .map(|w| Weights::I8(I8Weights::Owned(w)))
+    }
+}
+
+/// The header that starts off every layer, along with the offsets of the sizes (for error
+/// messages).
+struct Header {
+    nb_inputs: usize,
+    nb_neurons: usize,
+    activation: Activation,
+    precision: WeightPrecision,
+    scale: f32,
+    nb_inputs_offset: usize,
+    nb_neurons_offset: usize,
+}
+
+fn read_header<R: LayerReader>(r: &mut R, layer: &str) -> Result<Header, ModelError> {
+    let size = |r: &mut R| -> Result<(usize, usize), ModelError> {
+        let offset = r.offset();
+        let value = r.size(layer)?;
+        // Empty layers are useless, and the matrix code can't handle them.
+        if value > 0 {
+            Ok((value as usize, offset))
+        } else {
+            Err(ModelError::InvalidDimension {
+                layer: layer.to_owned(),
+                offset,
+                value,
+            })
+        }
+    };
+    let (nb_inputs, nb_inputs_offset) = size(r)?;
+    let (nb_neurons, nb_neurons_offset) = size(r)?;
+
+    let offset = r.offset();
+    let activation = match r.activation(layer)? {
+        0 => Activation::Tanh,
+        1 => Activation::Sigmoid,
+        2 => Activation::Relu,
+        value => {
+            return Err(ModelError::InvalidActivation {
+                layer: layer.to_owned(),
+                offset,
+                value,
+            })
+        }
+    };
+    let (precision, scale) = r.precision(layer)?;
+    Ok(Header {
+        nb_inputs,
+        nb_neurons,
+        activation,
+        precision,
+        scale,
+        nb_inputs_offset,
+        nb_neurons_offset,
+    })
+}
+
+impl Header {
+    /// Returns the product of `factors`, which are the dimensions of some of the layer's
+    /// weights. The sizes come straight from the model file, so this can overflow (especially on
+    /// 32-bit targets), in which case we blame the number of neurons.
+    fn weights_len(&self, layer: &str, factors: &[usize]) -> Result<usize, ModelError> {
+        factors
+            .iter()
+            .try_fold(1usize, |len, &x| len.checked_mul(x))
+            .ok_or_else(|| ModelError::InvalidDimension {
+                layer: layer.to_owned(),
+                offset: self.nb_neurons_offset,
+                value: self.nb_neurons as i32,
+            })
+    }
+}
+
+fn read_dense<R: LayerReader>(r: &mut R, layer: &str) -> Result<(DenseLayer, Header), ModelError> {
+    let h = read_header(r, layer)?;
+    let input_weights = r.weights(
+        layer,
+        h.precision,
+        h.weights_len(layer, &[h.nb_neurons, h.nb_inputs])?,
+    )?;
+    let bias = r.weights(layer, h.precision, h.nb_neurons)?;
+
+    let layer = DenseLayer {
+        nb_inputs: h.nb_inputs,
+        nb_neurons: h.nb_neurons,
+        input_weights,
+        bias,
+        scale: h.scale,
+        activation: h.activation,
+    };
+    Ok((layer, h))
+}
+
+fn read_gru<R: LayerReader>(r: &mut R, layer: &str) -> Result<(GruLayer, Header), ModelError> {
+    let h = read_header(r, layer)?;
+    let input_weights = r.weights(
+        layer,
+        h.precision,
+        h.weights_len(layer, &[3, h.nb_neurons, h.nb_inputs])?,
+    )?;
+    let recurrent_weights = r.weights(
+        layer,
+        h.precision,
+        h.weights_len(layer, &[3, h.nb_neurons, h.nb_neurons])?,
+    )?;
+    let bias = r.weights(
+        layer,
+        h.precision,
+        h.weights_len(layer, &[3, h.nb_neurons])?,
+    )?;
+
+    let layer = GruLayer {
+        nb_inputs: h.nb_inputs,
+        nb_neurons: h.nb_neurons,
+        input_weights,
+        recurrent_weights,
+        bias,
+        scale: h.scale,
+        activation: h.activation,
+    };
+    Ok((layer, h))
+}
+
+fn read_layer<R: LayerReader>(
+    r: &mut R,
+    layer: &str,
+    kind: LayerKind,
+) -> Result<(Layer, Header), ModelError> {
+    match kind {
+        LayerKind::Dense => read_dense(r, layer).map(|(l, h)| (Layer::Dense(l), h)),
+        LayerKind::Gru => read_gru(r, layer).map(|(l, h)| (Layer::Gru(l), h)),
+    }
+}
+
+fn check_trailing<R: LayerReader>(r: &R) -> Result<(), ModelError> {
+    if r.remaining() > 0 {
+        Err(ModelError::TrailingBytes {
+            offset: r.offset(),
+            len: r.remaining(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Reads the layers of the original RNNoise network, and checks that they fit together.
+pub(crate) fn read_model<R: LayerReader>(r: &mut R) -> Result<RnnModel, ModelError> {
+    let mut layers = Vec::new();
+    let mut headers = Vec::new();
+    for &(name, kind, _) in &RNNOISE_LAYERS {
+        let (layer, h) = read_layer(r, name, kind)?;
+        layers.push(layer);
+        headers.push(h);
+    }
+    check_trailing(r)?;
+
+    let model = RnnModel::rnnoise(layers);
+    check_sizes(&model, &headers)?;
+    Ok(model)
+}
+
+/// Reads a graph of layers, and checks that they fit together.
+pub(crate) fn read_graph(r: &mut BinaryReader) -> Result<RnnModel, ModelError> {
+    // Looks up a layer (or the features) by name.
+    let find = |nodes: &[Node], name: &str, offset: usize| {
+        if name == FEATURES {
+            Ok(Source::Features)
+        } else {
+            match nodes.iter().position(|n| n.name == name) {
+                Some(i) => Ok(Source::Layer(i)),
+                None => Err(ModelError::UnknownLayer {
+                    name: name.to_owned(),
+                    offset,
+                }),
+            }
+        }
+    };
+
+    let mut nodes = Vec::new();
+    let mut headers = Vec::new();
+    for _ in 0..r.u32("graph")? {
+        let offset = r.offset();
+        let name = r.string("graph")?;
+        if find(&nodes, &name, offset).is_ok() {
+            return Err(ModelError::DuplicateLayer { name, offset });
+        }
+
+        let offset = r.offset();
+        let kind = match r.take(&name, 1)?[0] {
+            0 => LayerKind::Dense,
+            1 => LayerKind::Gru,
+            value => {
+                return Err(ModelError::InvalidLayerKind {
+                    layer: name,
+                    offset,
+                    value: value as i32,
+                })
+            }
+        };
+
+        let mut inputs = Vec::new();
+        for _ in 0..r.u32(&name)? {
+            let offset = r.offset();
+            let input = r.string(&name)?;
+            inputs.push(find(&nodes, &input, offset)?);
+        }
+
+        let (layer, h) = read_layer(r, &name, kind)?;
+        nodes.push(Node {
+            name,
+            layer,
+            inputs,
+        });
+        headers.push(h);
+    }
+
+    // Reads the name of an output layer, which must be a layer and not the features.
+    let output = |r: &mut BinaryReader| -> Result<Option<usize>, ModelError> {
+        let offset = r.offset();
+        let name = r.string("graph")?;
+        if name.is_empty() {
+            return Ok(None);
+        }
+        match find(&nodes, &name, offset)? {
+            Source::Layer(i) => Ok(Some(i)),
+            Source::Features => Err(ModelError::UnknownLayer { name, offset }),
+        }
+    };
+    let offset = r.offset();
+    let gains = output(r)?.ok_or(ModelError::UnknownLayer {
+        name: String::new(),
+        offset,
+    })?;
+    let vad = output(r)?;
+    check_trailing(r)?;
+
+    let model = RnnModel {
+        nodes,
+        gains,
+        vad,
+        metadata: ModelMetadata::default(),
+    };
+    check_sizes(&model, &headers)?;
+    Ok(model)
+}
+
+/// Checks that the layers of a model fit together.
+fn check_sizes(model: &RnnModel, headers: &[Header]) -> Result<(), ModelError> {
+    let check = |field: String, offset: usize, expected: usize, actual: usize| {
+        if expected == actual {
+            Ok(())
+        } else {
+            Err(ModelError::DimensionMismatch {
+                field,
+                offset,
+                expected,
+                actual,
+            })
+        }
+    };
+
+    // The input to each layer is the concatenation of its sources.
+    for (node, h) in model.nodes.iter().zip(headers) {
+        let expected = node.inputs.iter().map(|&s| model.source_size(s)).sum();
+        check(
+            format!("{}.nb_inputs", node.name),
+            h.nb_inputs_offset,
+            expected,
+            node.layer.nb_inputs(),
+        )?;
+    }
+
+    // There is one gain for each band, and the voice activity is a single number.
+    let gains = &model.nodes[model.gains];
+    check(
+        format!("{}.nb_neurons", gains.name),
+        headers[model.gains].nb_neurons_offset,
+        crate::NB_BANDS,
+        gains.layer.nb_neurons(),
+    )?;
+    if let Some(vad) = model.vad {
+        let node = &model.nodes[vad];
+        check(
+            format!("{}.nb_neurons", node.name),
+            headers[vad].nb_neurons_offset,
+            1,
+            node.layer.nb_neurons(),
+        )?;
+    }
+    Ok(())
+}
@@ -8,7 +8,7 @@ use dasp_interpolate::{sinc::Sinc, Interpolator};
 use dasp_ring_buffer::Fixed;
 use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
 
-use nnnoiseless::{DenoiseState, RnnModel};
+use nnnoiseless::{DenoiseState, RnnModel, StreamingDenoiser};
 
 const FRAME_SIZE: usize = DenoiseState::FRAME_SIZE;
 
@@ -146,7 +146,7 @@ struct WavFrameWriter<W: Write + Seek> {
 
 impl<W: Write> FrameWriter for RawFrameWriter<W> {
     fn write_frame(&mut self, buf: &[f32]) -> Result<(), Error> {
-        assert_eq!(buf.len() * 2, self.buf.len());
+        self.buf.resize(buf.len() * 2, 0);
         for (dst, src) in self.buf.chunks_mut(2).zip(buf) {
             let bytes =
                 (src.max(i16::MIN as f32).min(i16::MAX as f32).round() as i16).to_le_bytes();
@@ -176,11 +176,26 @@ impl<W: Write + Seek> FrameWriter for WavFrameWriter<W> {
     }
 }
 
+/// Returns the sample rate that we should use for denoising audio that was sampled at
+/// `sample_rate`. If the denoiser supports `sample_rate` natively we use it directly; otherwise, we
+/// resample to 48kHz.
+fn denoising_rate(sample_rate: f64) -> f64 {
+    if DenoiseState::SAMPLE_RATES
+        .iter()
+        .any(|&r| r as f64 == sample_rate)
+    {
+        sample_rate
+    } else {
+        48_000.0
+    }
+}
+
 fn raw_samples<R: Read + 'static>(r: R, channels: usize, sample_rate: f64) -> Box<dyn ReadSample> {
     let raw = IterReadSample::new(RawSampleIter { bytes: r.bytes() }, channels);
 
-    if sample_rate != 48_000.0 {
-        Box::new(raw.resampled(sample_rate / 48_000.0))
+    let rate = denoising_rate(sample_rate);
+    if sample_rate != rate {
+        Box::new(raw.resampled(sample_rate / rate))
     } else {
         Box::new(raw)
     }
@@ -188,6 +203,7 @@ fn raw_samples<R: Read + 'static>(r: R, channels: usize, sample_rate: f64) -> Bo
 
 fn wav_samples<R: Read + 'static>(wav: WavReader<R>) -> Box<dyn ReadSample> {
     let sample_rate = wav.spec().sample_rate as f64;
+    let rate = denoising_rate(sample_rate);
     let channels = wav.spec().channels as usize;
     match wav.spec().sample_format {
         SampleFormat::Int => {
@@ -206,8 +222,8 @@ fn wav_samples<R: Read + 'static>(wav: WavReader<R>) -> Box<dyn ReadSample> {
             });
 
             let read_sample = IterReadSample::new(iter, channels);
-            if sample_rate != 48_000.0 {
-                Box::new(read_sample.resampled(sample_rate / 48_000.0))
+            if sample_rate != rate {
+                Box::new(read_sample.resampled(sample_rate / rate))
             } else {
                 Box::new(read_sample)
             }
@@ -215,11 +231,11 @@ fn wav_samples<R: Read + 'static>(wav: WavReader<R>) -> Box<dyn ReadSample> {
         SampleFormat::Float => {
             let iter = wav
                 .into_samples::<f32>()
-                .map(|s| s.map(|s| s * 32767.0).map_err(|e| e.into()));
+                .map(|s| s.map(|s| s * 32768.0).map_err(|e| e.into()));
 
             let read_sample = IterReadSample::new(iter, channels);
-            if sample_rate != 48_000.0 {
-                Box::new(read_sample.resampled(sample_rate / 48_000.0))
+            if sample_rate != rate {
+                Box::new(read_sample.resampled(sample_rate / rate))
             } else {
                 Box::new(read_sample)
             }
@@ -227,11 +243,96 @@ fn wav_samples<R: Read + 'static>(wav: WavReader<R>) -> Box<dyn ReadSample> {
     }
 }
 
+fn read_model(path: &str) -> Result<RnnModel, Error> {
+    if let Some(name) = path.strip_prefix("builtin:") {
+        return RnnModel::builtin(name).with_context(|| {
+            let names: Vec<_> = RnnModel::builtin_names().collect();
+            format!(
+                "Unknown built-in model \"{}\" (available models: {})",
+                name,
+                names.join(", ")
+            )
+        });
+    }
+
+    let data = std::fs::read(path).context("Failed to open model file")?;
+    // Models in the rnnoise-nu text format start with a header; anything else we assume
+    // is in our binary format.
+    let model = match std::str::from_utf8(&data) {
+        Ok(text) if text.starts_with("rnnoise-nu") => RnnModel::from_rnnoise_text(text),
+        _ => RnnModel::from_bytes(&data),
+    };
+    model.context("Failed to parse model file")
+}
+
+fn print_model_info(model: &RnnModel) {
+    let metadata = model.metadata();
+    if let Some(name) = &metadata.name {
+        println!("Name: {}", name);
+    }
+    if let Some(training_data) = &metadata.training_data {
+        println!("Training data: {}", training_data);
+    }
+    if let Some(rate) = metadata.sample_rate {
+        println!("Sample rate: {}", rate);
+    }
+    for (key, value) in &metadata.extra {
+        println!("{}: {}", key, value);
+    }
+    println!("Parameters: {}", model.nb_params());
+    println!("Gains: {}", model.gains_layer());
+    println!("Voice activity: {}", model.vad_layer().unwrap_or("(none)"));
+    println!();
+
+    println!(
+        "{:<16}{:<7}{:>7}{:>8}  {:<9}{:<6}{:>7}{:>9}{:>9}{:>9}{:>9}{:>7}  Input from",
+        "Layer",
+        "Kind",
+        "Inputs",
+        "Neurons",
+        "Act.",
+        "Type",
+        "Params",
+        "Min",
+        "Max",
+        "Mean",
+        "Std dev",
+        "Zeros"
+    );
+    for layer in model.layers() {
+        let w = &layer.weights;
+        println!(
+            "{:<16}{:<7}{:>7}{:>8}  {:<9}{:<6}{:>7}{:>9.4}{:>9.4}{:>9.4}{:>9.4}{:>7}  {}",
+            layer.name,
+            format!("{:?}", layer.kind),
+            layer.nb_inputs,
+            layer.nb_neurons,
+            format!("{:?}", layer.activation),
+            format!("{:?}", layer.precision),
+            layer.nb_params,
+            w.min,
+            w.max,
+            w.mean,
+            w.std_dev,
+            w.zeros,
+            layer.inputs.join(", ")
+        );
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches =
         Command::new("nnnoiseless")
             .version(crate_version!())
             .about("Remove noise from audio files")
+            .after_help("The output has the same sample rate as the input if that is 8, 12, 16, 24 or 48kHz; otherwise, it is resampled to 48kHz.")
+            .subcommand_negates_reqs(true)
+            .args_conflicts_with_subcommands(true)
+            .subcommand(
+                Command::new("model-info")
+                    .about("Print information about a model file")
+                    .arg(arg!(<FILE> "model file, or builtin:<NAME> for a built-in model")),
+            )
             .arg(arg!(<INPUT> "input audio file"))
             .arg(arg!(<OUTPUT> "output audio file"))
             .arg(arg!(--"wav-in" "the input is a wav file (default is to detect wav files by their filename"))
@@ -244,9 +345,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .required(false)
                     .validator(|s| s.parse::<u16>()),
             )
-            .arg(arg!(--model <PATH> "path to a custom model file").required(false))
+            .arg(arg!(--model <PATH> "path to a custom model file, or builtin:<NAME> for a built-in model").required(false))
+            .arg(
+                arg!(--"max-attenuation" <DB> "the maximum amount (in dB) by which to attenuate noise (defaults to no limit)")
+                    .required(false)
+                    .validator(|s| s.parse::<f32>()),
+            )
+            .arg(
+                arg!(--mix <MIX> "the proportion of denoised audio in the output, between 0 and 1 (defaults to 1)")
+                    .required(false)
+                    .validator(|s| s.parse::<f32>()),
+            )
             .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("model-info") {
+        let model = read_model(matches.value_of("FILE").unwrap())?;
+        print_model_info(&model);
+        return Ok(());
+    }
+
     let in_name = matches.value_of("INPUT").unwrap();
     let out_name = matches.value_of("OUTPUT").unwrap();
     let in_file = BufReader::new(
@@ -262,23 +379,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let out_wav =
         matches.is_present("wav-out") || Path::new(out_name).extension() == Some("wav".as_ref());
 
-    let (mut samples, channels) = if in_wav {
+    let (mut samples, channels, sample_rate) = if in_wav {
         let wav_reader = WavReader::new(in_file)?;
         let channels = wav_reader.spec().channels;
-        (wav_samples(wav_reader), channels)
+        let sample_rate = wav_reader.spec().sample_rate as f64;
+        (wav_samples(wav_reader), channels, sample_rate)
     } else {
         let sample_rate = matches.value_of_t("sample-rate").unwrap_or(48_000.0);
         let channels = matches.value_of_t("channels").unwrap_or(1);
         (
             raw_samples(in_file, channels as usize, sample_rate),
             channels,
+            sample_rate,
         )
     };
+    let sample_rate = denoising_rate(sample_rate) as u32;
 
     let mut frame_writer: Box<dyn FrameWriter> = if out_wav {
         let spec = WavSpec {
             channels,
-            sample_rate: 48_000,
+            sample_rate,
             bits_per_sample: 16,
             sample_format: SampleFormat::Int,
         };
@@ -287,46 +407,69 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     } else {
         Box::new(RawFrameWriter {
             writer: out_file,
-            buf: vec![0; FRAME_SIZE * 2],
+            buf: Vec::new(),
         })
     };
 
     let model = if let Some(model_path) = matches.value_of("model") {
-        let data = std::fs::read(model_path).context("Failed to open model file")?;
-        RnnModel::from_bytes(&data).context("Failed to parse model file")?
+        read_model(model_path)?
     } else {
         RnnModel::default()
     };
 
     let channels = channels as usize;
-    let mut in_bufs = vec![vec![0.0; FRAME_SIZE]; channels];
-    let mut out_bufs = vec![vec![0.0; FRAME_SIZE]; channels];
-    let mut out_buf = vec![0.0; FRAME_SIZE * channels];
-    let mut states = vec![DenoiseState::with_model(&model); channels];
-    let mut first = true;
-    'outer: loop {
-        for i in 0..FRAME_SIZE {
+    let mut in_bufs = vec![Vec::with_capacity(FRAME_SIZE); channels];
+    let mut out_bufs = vec![Vec::with_capacity(FRAME_SIZE); channels];
+    let mut out_buf = Vec::with_capacity(FRAME_SIZE * channels);
+    let mut denoiser = StreamingDenoiser::with_model(&model);
+    denoiser.state_mut().set_sample_rate(sample_rate);
+    let mut denoisers = vec![denoiser; channels];
+    if let Ok(db) = matches.value_of_t("max-attenuation") {
+        for denoiser in &mut denoisers {
+            denoiser.state_mut().set_max_attenuation(db);
+        }
+    }
+    if let Ok(mix) = matches.value_of_t("mix") {
+        for denoiser in &mut denoisers {
+            denoiser.state_mut().set_mix(mix);
+        }
+    }
+    let mut done = false;
+    while !done {
+        for buf in &mut in_bufs {
+            buf.clear();
+        }
+        for _ in 0..FRAME_SIZE {
             if let Some(buf) = samples.next_sample()? {
-                for j in 0..channels {
-                    in_bufs[j][i] = buf[j];
+                for (in_buf, &x) in in_bufs.iter_mut().zip(buf) {
+                    in_buf.push(x);
                 }
             } else {
-                break 'outer;
+                done = true;
+                break;
             }
         }
 
-        for j in 0..channels {
-            states[j].process_frame(&mut out_bufs[j], &in_bufs[j]);
+        for (j, denoiser) in denoisers.iter_mut().enumerate() {
+            denoiser.process(&in_bufs[j], &mut out_bufs[j]);
+            if done {
+                // Process the final partial frame, so that the output is as long as the input.
+                denoiser.flush(&mut out_bufs[j]);
+            }
         }
-        if !first {
-            for i in 0..FRAME_SIZE {
-                for j in 0..channels {
-                    out_buf[i * channels + j] = out_bufs[j][i];
-                }
+
+        out_buf.clear();
+        for i in 0..out_bufs[0].len() {
+            for out in &out_bufs {
+                out_buf.push(out[i]);
             }
+        }
+        if !out_buf.is_empty() {
             frame_writer.write_frame(&out_buf[..])?;
         }
-        first = false;
+        for buf in &mut out_bufs {
+            buf.clear();
+        }
     }
     frame_writer.finalize()?;
 
@@ -1,3 +1,8 @@
+use alloc::{vec, vec::Vec};
+
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
+
 use crate::{PITCH_BUF_SIZE, PITCH_FRAME_SIZE, PITCH_MAX_PERIOD, PITCH_MIN_PERIOD};
 
 #[derive(Clone)]
@@ -17,6 +22,18 @@ pub(crate) struct PitchFinder {
 }
 
 impl PitchFinder {
+    /// Returns the period and gain of the pitch that we found last time. These are the only
+    /// things that we remember from one frame to the next.
+    pub(crate) fn last_pitch(&self) -> (usize, f32) {
+        (self.last_period, self.last_gain)
+    }
+
+    /// Sets the period and gain returned by [`PitchFinder::last_pitch`].
+    pub(crate) fn set_last_pitch(&mut self, period: usize, gain: f32) {
+        self.last_period = period;
+        self.last_gain = gain;
+    }
+
     pub(crate) fn new() -> PitchFinder {
         assert!(
             PITCH_MAX_PERIOD + 1
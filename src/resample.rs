@@ -0,0 +1,198 @@
+//! Integer-ratio resampling, for running the denoiser at sample rates below 48kHz.
+//!
+//! The neural network only knows about 48kHz audio, so for lower sample rates we upsample each
+//! frame to 48kHz, denoise it, and then downsample it again. Both directions use the same
+//! windowed-sinc low-pass filter, running at 48kHz.
+
+use alloc::vec::Vec;
+
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
+
+use crate::{Error, FRAME_SIZE};
+
+/// The number of taps in the low-pass filter. `TAPS - 1` must be divisible by every supported
+/// resampling factor, so that the filter delay is a whole number of samples at the low rate.
+const TAPS: usize = 193;
+const HIST: usize = TAPS - 1;
+
+/// The state of a [`Resampler`], as saved in a [`DenoiseSnapshot`](crate::DenoiseSnapshot).
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct ResamplerSnapshot {
+    factor: usize,
+    up_mem: Vec<f32>,
+    down_mem: Vec<f32>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Resampler {
+    factor: usize,
+    filter: [f32; TAPS],
+    /// The upsampled (but not yet filtered) input. The first `HIST` elements are left over from
+    /// the previous frame.
+    up_mem: [f32; HIST + FRAME_SIZE],
+    /// The denoised 48kHz signal. The first `HIST` elements are left over from the previous frame.
+    down_mem: [f32; HIST + FRAME_SIZE],
+}
+
+impl Resampler {
+    /// Creates a resampler for converting between 48kHz and `48kHz / factor`.
+    ///
+    /// `factor` must divide both `FRAME_SIZE` and `TAPS - 1`.
+    pub(crate) fn new(factor: usize) -> Resampler {
+        // The cutoff frequency, as a fraction of 48kHz. This leaves enough room for the filter's
+        // transition band below the Nyquist frequency of the low sample rate.
+        let cutoff = 0.42 / factor as f64;
+        let pi = core::f64::consts::PI;
+        let center = HIST as f64 / 2.0;
+        let mut taps = [0.0f64; TAPS];
+        for (k, h) in taps.iter_mut().enumerate() {
+            let t = k as f64 - center;
+            let sinc = if t == 0.0 {
+                2.0 * cutoff
+            } else {
+                (2.0 * pi * cutoff * t).sin() / (pi * t)
+            };
+            // Blackman window.
+            let phase = 2.0 * pi * k as f64 / HIST as f64;
+            let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+            *h = sinc * window;
+        }
+
+        // Normalize for unit gain at DC.
+        let sum: f64 = taps.iter().sum();
+        let mut filter = [0.0; TAPS];
+        for (f, h) in filter.iter_mut().zip(&taps) {
+            *f = (h / sum) as f32;
+        }
+
+        Resampler {
+            factor,
+            filter,
+            up_mem: [0.0; HIST + FRAME_SIZE],
+            down_mem: [0.0; HIST + FRAME_SIZE],
+        }
+    }
+
+    /// Forgets the previous input and output.
+    pub(crate) fn reset(&mut self) {
+        self.up_mem = [0.0; HIST + FRAME_SIZE];
+        self.down_mem = [0.0; HIST + FRAME_SIZE];
+    }
+
+    /// Saves the parts of the signal that are carried over to the next frame.
+    pub(crate) fn snapshot(&self) -> ResamplerSnapshot {
+        ResamplerSnapshot {
+            factor: self.factor,
+            up_mem: self.up_mem[..HIST].to_vec(),
+            down_mem: self.down_mem[..HIST].to_vec(),
+        }
+    }
+
+    /// Restores the state saved by [`Resampler::snapshot`].
+    pub(crate) fn restore(&mut self, snapshot: &ResamplerSnapshot) -> Result<(), Error> {
+        if snapshot.factor != self.factor
+            || snapshot.up_mem.len() != HIST
+            || snapshot.down_mem.len() != HIST
+        {
+            return Err(Error::InvalidSnapshot);
+        }
+        self.up_mem[..HIST].copy_from_slice(&snapshot.up_mem);
+        self.down_mem[..HIST].copy_from_slice(&snapshot.down_mem);
+        Ok(())
+    }
+
+    /// The number of samples in a frame at the low sample rate.
+    pub(crate) fn frame_size(&self) -> usize {
+        FRAME_SIZE / self.factor
+    }
+
+    /// The number of samples (at the low sample rate) by which the resampling delays the signal.
+    pub(crate) fn delay(&self) -> usize {
+        HIST / self.factor
+    }
+
+    /// Upsamples `input` (which has length `FRAME_SIZE / factor`) into `output` (which has length
+    /// `FRAME_SIZE`).
+    pub(crate) fn upsample(&mut self, output: &mut [f32], input: &[f32]) {
+        let new = &mut self.up_mem[HIST..];
+        for x in new.iter_mut() {
+            *x = 0.0;
+        }
+        // Stuffing zeros between the samples loses energy, so compensate for that.
+        for (i, &x) in input.iter().enumerate() {
+            new[i * self.factor] = x * self.factor as f32;
+        }
+
+        // The filter is symmetric, so we don't need to reverse it.
+        for (n, out) in output.iter_mut().enumerate() {
+            *out = dot(&self.up_mem[n..(n + TAPS)], &self.filter);
+        }
+        self.up_mem.copy_within(FRAME_SIZE.., 0);
+    }
+
+    /// Downsamples `input` (which has length `FRAME_SIZE`) into `output` (which has length
+    /// `FRAME_SIZE / factor`).
+    pub(crate) fn downsample(&mut self, output: &mut [f32], input: &[f32]) {
+        self.down_mem[HIST..].copy_from_slice(input);
+        for (m, out) in output.iter_mut().enumerate() {
+            let n = m * self.factor;
+            *out = dot(&self.down_mem[n..(n + TAPS)], &self.filter);
+        }
+        self.down_mem.copy_within(FRAME_SIZE.., 0);
+    }
+}
+
+fn dot(xs: &[f32], ys: &[f32]) -> f32 {
+    xs.iter().zip(ys).map(|(x, y)| x * y).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sin(freq: f32, rate: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|x| (x as f32 * freq * 2.0 * std::f32::consts::PI / rate).sin())
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for &factor in &[2, 3, 4, 6] {
+            let rate = 48_000.0 / factor as f32;
+            let frame = FRAME_SIZE / factor;
+            let input = sin(440.0, rate, frame * 20);
+            let mut output = vec![0.0; input.len()];
+            let mut resampler = Resampler::new(factor);
+            let mut buf = [0.0; FRAME_SIZE];
+            for (out, inp) in output.chunks_mut(frame).zip(input.chunks(frame)) {
+                resampler.upsample(&mut buf, inp);
+                resampler.downsample(out, &buf);
+            }
+
+            let delay = resampler.delay();
+            for (x, y) in input.iter().zip(&output[delay..]).skip(TAPS) {
+                assert!((x - y).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn upsample() {
+        // A tone at 16kHz should come out as the same tone at 48kHz.
+        let input = sin(1000.0, 16_000.0, 160 * 10);
+        let expected = sin(1000.0, 48_000.0, FRAME_SIZE * 10);
+        let mut output = vec![0.0; FRAME_SIZE * 10];
+        let mut resampler = Resampler::new(3);
+        for (out, inp) in output.chunks_mut(FRAME_SIZE).zip(input.chunks(160)) {
+            resampler.upsample(out, inp);
+        }
+
+        let delay = HIST / 2;
+        for (x, y) in expected.iter().zip(&output[delay..]).skip(TAPS) {
+            assert!((x - y).abs() < 1e-3);
+        }
+    }
+}
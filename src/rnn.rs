@@ -1,31 +1,277 @@
-use std::borrow::Cow;
+use alloc::{borrow::ToOwned, boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use core::ops::Deref;
+#[cfg(feature = "std")]
+use std::io::{Read, Write};
 
-use crate::util::{relu, sigmoid_approx, tansig_approx, zip3};
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
 
-const MAX_NEURONS: usize = 128;
+use crate::container::{self, Architecture};
+use crate::model_reader::{self, BinaryFormat, BinaryReader, StoreWeights, TextReader};
+use crate::simd::{self, Isa, Weight};
+use crate::util::{relu, zip3};
+use crate::{Error, ModelError, ModelMetadata};
+
+/// The first line of a model file in the `rnnoise-nu` text format.
+const RNNOISE_TEXT_HEADER: &str = "rnnoise-nu model file version 1";
 
 // It's annoying to expose a public API with `i8`s, because `include_bytes` works with `u8`s only.
 // So we do conversions from `&[i8]` to `&[u8]` internally. Hopefully at some point rust will have
 // a safe API for this...
 fn to_i8(x: &[u8]) -> &[i8] {
-    unsafe { std::slice::from_raw_parts(x.as_ptr() as *const i8, x.len()) }
+    unsafe { core::slice::from_raw_parts(x.as_ptr() as *const i8, x.len()) }
+}
+
+fn to_u8(x: &[i8]) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(x.as_ptr() as *const u8, x.len()) }
+}
+
+/// Writes the header that starts off every layer: the sizes and activation function and, in the
+/// container format, the precision and scale of the weights.
+fn write_header(
+    buf: &mut Vec<u8>,
+    nb_inputs: usize,
+    nb_neurons: usize,
+    activation: Activation,
+    weights: &Weights,
+    scale: f32,
+    format: BinaryFormat,
+) -> Result<(), container::WriteError> {
+    let max = match format {
+        BinaryFormat::Legacy => i8::MAX as usize,
+        BinaryFormat::Container => u16::MAX as usize,
+    };
+    if nb_inputs > max || nb_neurons > max {
+        return Err("layer is too large for the model format");
+    }
+
+    match format {
+        BinaryFormat::Legacy => {
+            if weights.precision() != WeightPrecision::I8 || scale != WEIGHTS_SCALE {
+                return Err("layer weights can't be stored in the legacy model format");
+            }
+            buf.extend_from_slice(&[nb_inputs as u8, nb_neurons as u8, activation as u8]);
+        }
+        BinaryFormat::Container => {
+            buf.extend_from_slice(&(nb_inputs as u16).to_le_bytes());
+            buf.extend_from_slice(&(nb_neurons as u16).to_le_bytes());
+            buf.extend_from_slice(&[activation as u8, weights.precision() as u8]);
+            buf.extend_from_slice(&scale.to_le_bytes());
+        }
+    }
+    Ok(())
 }
 
-#[derive(Clone, Copy, Debug)]
+/// The activation function of a layer in an [`RnnModel`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Activation {
+    /// The hyperbolic tangent function.
     Tanh = 0,
+    /// The logistic function, `1 / (1 + exp(-x))`.
     Sigmoid = 1,
+    /// The rectified linear unit, `max(x, 0)`.
     Relu = 2,
 }
 
-const WEIGHTS_SCALE: f32 = 1.0 / 256.0;
+/// The scale of the `i8` weights in the legacy and text formats: a stored weight of `x`
+/// represents the value `x / 256`.
+pub(crate) const WEIGHTS_SCALE: f32 = 1.0 / 256.0;
+
+/// The numeric type in which the weights of a layer are stored.
+///
+/// Whatever the precision, the stored weights are multiplied by a per-layer scale to get their
+/// actual values. The legacy and text model formats only support `I8` weights with a scale of
+/// `1 / 256`, which limits the weights to the range `[-0.5, 0.5)`; the container format
+/// supports all precisions and any scale.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WeightPrecision {
+    /// Signed bytes.
+    I8 = 0,
+    /// 16-bit signed integers.
+    I16 = 1,
+    /// 32-bit floats.
+    F32 = 2,
+}
+
+/// The weights (or biases) of a layer, in the precision in which they were stored.
+#[derive(Clone, Debug)]
+pub(crate) enum Weights {
+    I8(I8Weights),
+    I16(Vec<i16>),
+    F32(Vec<f32>),
+}
+
+/// Model data that can be shared between models. See [`RnnModel::from_shared_bytes`].
+pub(crate) type SharedBytes = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/// Weights of type `i8`. These have the same representation in the model files as in memory, so
+/// instead of copying them we can point into the model data if it sticks around for long enough.
+#[derive(Clone)]
+pub(crate) enum I8Weights {
+    Owned(Vec<i8>),
+    Static(&'static [i8]),
+    /// The weights are `data[start..(start + len)]`.
+    Shared {
+        data: SharedBytes,
+        start: usize,
+        len: usize,
+    },
+}
+
+impl Deref for I8Weights {
+    type Target = [i8];
+
+    fn deref(&self) -> &[i8] {
+        match self {
+            I8Weights::Owned(w) => w,
+            I8Weights::Static(w) => w,
+            I8Weights::Shared { data, start, len } => {
+                to_i8(&(**data).as_ref()[*start..(*start + *len)])
+            }
+        }
+    }
+}
+
+impl core::fmt::Debug for I8Weights {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl From<Vec<i8>> for I8Weights {
+    fn from(w: Vec<i8>) -> I8Weights {
+        I8Weights::Owned(w)
+    }
+}
+
+impl Weights {
+    pub(crate) fn precision(&self) -> WeightPrecision {
+        match self {
+            Weights::I8(_) => WeightPrecision::I8,
+            Weights::I16(_) => WeightPrecision::I16,
+            Weights::F32(_) => WeightPrecision::F32,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Weights::I8(w) => w.len(),
+            Weights::I16(w) => w.len(),
+            Weights::F32(w) => w.len(),
+        }
+    }
+
+    /// Returns the stored weights, converted to `f32` but not scaled.
+    fn values(&self) -> Box<dyn Iterator<Item = f32> + '_> {
+        match self {
+            Weights::I8(w) => Box::new(w.iter().map(|&x| x.to_f32())),
+            Weights::I16(w) => Box::new(w.iter().map(|&x| x.to_f32())),
+            Weights::F32(w) => Box::new(w.iter().copied()),
+        }
+    }
+
+    /// Fills `dst` with the stored weights starting at index `start`.
+    fn copy_to(&self, dst: &mut [f32], start: usize) {
+        fn copy<T: Weight>(dst: &mut [f32], src: &[T]) {
+            for (x, y) in dst.iter_mut().zip(src) {
+                *x = y.to_f32();
+            }
+        }
+        match self {
+            Weights::I8(w) => copy(dst, &w[start..]),
+            Weights::I16(w) => copy(dst, &w[start..]),
+            Weights::F32(w) => copy(dst, &w[start..]),
+        }
+    }
+
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Weights::I8(xs) => buf.extend_from_slice(to_u8(xs)),
+            Weights::I16(xs) => xs
+                .iter()
+                .for_each(|x| buf.extend_from_slice(&x.to_le_bytes())),
+            Weights::F32(xs) => xs
+                .iter()
+                .for_each(|x| buf.extend_from_slice(&x.to_le_bytes())),
+        }
+    }
+}
+
+/// The kind of a layer in an [`RnnModel`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayerKind {
+    /// A fully-connected layer.
+    Dense,
+    /// A gated recurrent unit.
+    Gru,
+}
+
+/// A description of a layer in an [`RnnModel`], as returned by [`RnnModel::layers`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerInfo {
+    /// The name of the layer, for example `"noise_gru"`.
+    pub name: String,
+    /// What kind of layer this is.
+    pub kind: LayerKind,
+    /// The names of the layers whose outputs are concatenated to form the input to this layer.
+    /// The input features of the model are called `"features"`.
+    pub inputs: Vec<String>,
+    /// The number of inputs to the layer.
+    pub nb_inputs: usize,
+    /// The number of neurons in the layer (which is also the number of outputs).
+    pub nb_neurons: usize,
+    /// The layer's activation function.
+    pub activation: Activation,
+    /// The numeric type in which the layer's parameters are stored.
+    pub precision: WeightPrecision,
+    /// The number of parameters (weights and biases) in the layer.
+    pub nb_params: usize,
+    /// Some statistics about the layer's parameters.
+    pub weights: WeightStats,
+}
+
+/// Summary statistics of the parameters (weights and biases) of a layer.
+///
+/// The statistics are computed from the actual values of the parameters (that is, after applying
+/// the layer's scale), not from their representation in the model file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WeightStats {
+    /// The smallest parameter.
+    pub min: f32,
+    /// The largest parameter.
+    pub max: f32,
+    /// The mean of the parameters.
+    pub mean: f32,
+    /// The standard deviation of the parameters.
+    pub std_dev: f32,
+    /// The number of parameters that are exactly zero.
+    pub zeros: usize,
+}
+
+impl WeightStats {
+    fn new(params: &[&Weights], scale: f32) -> WeightStats {
+        let values = || params.iter().flat_map(|p| p.values()).map(|x| x * scale);
+        let count = values().count().max(1) as f32;
+        let mean = values().sum::<f32>() / count;
+        let var = values().map(|x| (x - mean) * (x - mean)).sum::<f32>() / count;
+        WeightStats {
+            min: values().fold(f32::INFINITY, f32::min),
+            max: values().fold(f32::NEG_INFINITY, f32::max),
+            mean,
+            std_dev: var.sqrt(),
+            zeros: values().filter(|&x| x == 0.0).count(),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct DenseLayer {
     /// An array of length `nb_neurons`.
-    pub bias: Cow<'static, [i8]>,
+    pub bias: Weights,
     /// An array of length `nb_inputs * nb_neurons`.
-    pub input_weights: Cow<'static, [i8]>,
+    pub input_weights: Weights,
+    /// The factor by which the stored weights and biases are multiplied.
+    pub scale: f32,
     pub nb_inputs: usize,
     pub nb_neurons: usize,
     pub activation: Activation,
@@ -34,46 +280,158 @@ pub struct DenseLayer {
 #[derive(Clone)]
 pub struct GruLayer {
     /// An array of length `3 * nb_neurons`.
-    pub bias: Cow<'static, [i8]>,
+    pub bias: Weights,
     /// An array of length `3 * nb_inputs * nb_neurons`.
-    pub input_weights: Cow<'static, [i8]>,
+    pub input_weights: Weights,
     /// An array of length `3 * nb_neurons^2`.
-    pub recurrent_weights: Cow<'static, [i8]>,
+    pub recurrent_weights: Weights,
+    /// The factor by which the stored weights and biases are multiplied.
+    pub scale: f32,
     pub nb_inputs: usize,
     pub nb_neurons: usize,
     pub activation: Activation,
 }
 
+#[derive(Clone)]
+pub enum Layer {
+    Dense(DenseLayer),
+    Gru(GruLayer),
+}
+
+/// The name by which layers refer to the input features.
+pub(crate) const FEATURES: &str = "features";
+
+/// Something that a layer can take its input from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// The features computed from the input signal.
+    Features,
+    /// The output of the layer with this index.
+    Layer(usize),
+}
+
+/// A layer in an [`RnnModel`], together with its name and inputs.
+#[derive(Clone)]
+pub struct Node {
+    pub name: String,
+    pub layer: Layer,
+    /// The outputs of these sources are concatenated to form the input to `layer`.
+    pub inputs: Vec<Source>,
+}
+
+/// The layers of the original RNNoise network, along with their kinds and inputs.
+pub(crate) const RNNOISE_LAYERS: [(&str, LayerKind, &[&str]); 6] = [
+    ("input_dense", LayerKind::Dense, &[FEATURES]),
+    ("vad_gru", LayerKind::Gru, &["input_dense"]),
+    (
+        "noise_gru",
+        LayerKind::Gru,
+        &["input_dense", "vad_gru", FEATURES],
+    ),
+    (
+        "denoise_gru",
+        LayerKind::Gru,
+        &["vad_gru", "noise_gru", FEATURES],
+    ),
+    ("denoise_output", LayerKind::Dense, &["denoise_gru"]),
+    ("vad_output", LayerKind::Dense, &["vad_gru"]),
+];
+
 /// An `RnnModel` contains all the model parameters for the denoising algorithm.
 /// `nnnoiseless` has a built-in model that should work for most purposes, but if you have
 /// specific needs then you might benefit from training a custom model. Scripts for model
 /// training are available as part of [`RNNoise`]; once the model is trained, you can load it
 /// here.
 ///
+/// A model is a sequence of layers, each of which takes its input from the input features and
+/// from the outputs of the layers before it. One layer produces the gains of the frequency bands,
+/// and (optionally) another one produces the probability of voice activity. Most models have
+/// the same layers as the original RNNoise network, but other arrangements can be stored in our
+/// container format (see [`RnnModel::to_bytes`]).
+///
 /// [`RNNoise`]: https://github.com/xiph/rnnoise
 #[derive(Clone)]
 pub struct RnnModel {
-    pub(crate) input_dense: DenseLayer,
-    pub(crate) vad_gru: GruLayer,
-    pub(crate) noise_gru: GruLayer,
-    pub(crate) denoise_gru: GruLayer,
-    pub(crate) denoise_output: DenseLayer,
-    pub(crate) vad_output: DenseLayer,
+    /// The layers, in the order in which they are evaluated.
+    pub(crate) nodes: Vec<Node>,
+    /// The index of the layer that outputs the band gains.
+    pub(crate) gains: usize,
+    /// The index of the layer that outputs the voice activity probability, if there is one.
+    pub(crate) vad: Option<usize>,
+    pub(crate) metadata: ModelMetadata,
 }
 
 #[derive(Clone)]
 pub struct RnnState<'model> {
-    model: Cow<'model, RnnModel>,
-    vad_gru_state: Vec<f32>,
-    noise_gru_state: Vec<f32>,
-    denoise_gru_state: Vec<f32>,
+    model: ModelRef<'model>,
+    /// The output of each layer. For GRU layers, this is also the layer's state.
+    outputs: Vec<Vec<f32>>,
+    /// The input to the current layer.
+    input_buf: Vec<f32>,
+    /// Temporary storage for the GRU layers.
+    gru_buf: Vec<f32>,
+    /// The instruction set used to run the model.
+    isa: Isa,
 }
 
 impl RnnModel {
-    /// Reads an `RnnModel` from an array of bytes, in the format produced by the
+    /// Reads an `RnnModel` from an array of bytes, in the format produced by
+    /// [`RnnModel::to_bytes`] or by the `nnnoiseless` training scripts.
+    ///
+    /// Model files written by `RnnModel::to_bytes` have a header (containing a version number and
+    /// some [`ModelMetadata`]) and a checksum, while older model files (including the ones
+    /// written by the training scripts) consist of just the layers. Both kinds are supported.
+    ///
+    /// If the data is invalid, the returned [`ModelError`] describes what went wrong.
+    pub fn from_bytes(bytes: &[u8]) -> Result<RnnModel, ModelError> {
+        RnnModel::from_file_data(bytes, &|xs, _| I8Weights::Owned(xs.to_owned()))
+    }
+
+    /// Reads an `RnnModel` from `r`, in the format produced by [`RnnModel::to_bytes`] or by the
     /// `nnnoiseless` training scripts.
-    pub fn from_bytes(bytes: &[u8]) -> Option<RnnModel> {
-        RnnModel::from_bytes_impl(to_i8(bytes), |xs| Cow::Owned(xs.to_owned()))
+    ///
+    /// This reads all of `r` into memory. If the data can't be read, the I/O error is returned;
+    /// if it isn't a valid model, the returned error has kind [`std::io::ErrorKind::InvalidData`]
+    /// and wraps a [`ModelError`] describing what went wrong.
+    ///
+    /// This is only available with the `std` feature.
+    #[cfg(feature = "std")]
+    pub fn from_reader<R: Read>(mut r: R) -> std::io::Result<RnnModel> {
+        let mut data = Vec::new();
+        r.read_to_end(&mut data)?;
+        RnnModel::from_shared_bytes(data)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Reads an `RnnModel` from some bytes that can be shared, in the format produced by
+    /// [`RnnModel::to_bytes`] or by the `nnnoiseless` training scripts.
+    ///
+    /// Like [`RnnModel::from_static_bytes`], the returned model stores references into `bytes`
+    /// instead of copying the weights (at least, the ones stored as `i8`s). Unlike it, `bytes`
+    /// doesn't need to live forever: it is kept alive by the model (and its clones), and dropped
+    /// along with the last of them. Cloning the model doesn't copy `bytes`.
+    ///
+    /// `bytes` can be anything that holds bytes and can be sent between threads, for example a
+    /// `Vec<u8>`, an `Arc<[u8]>` or a memory-mapped file. Its `as_ref` method must always return
+    /// the same bytes. For example, to share one copy of a large model file between threads or
+    /// processes, you could map it into memory with the `memmap2` crate:
+    ///
+    /// ```ignore
+    /// let file = std::fs::File::open("/path/to/model/weights.rnn")?;
+    /// // Safety: the file must not be modified while it's mapped.
+    /// let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    /// let model = RnnModel::from_shared_bytes(mmap).expect("Corrupted model file");
+    /// ```
+    pub fn from_shared_bytes<T>(bytes: T) -> Result<RnnModel, ModelError>
+    where
+        T: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        let data: SharedBytes = Arc::new(bytes);
+        RnnModel::from_file_data((*data).as_ref(), &|xs, start| I8Weights::Shared {
+            data: Arc::clone(&data),
+            start,
+            len: xs.len(),
+        })
     }
 
     /// Reads an `RnnModel` from a static array of bytes, in the format produced by the
@@ -89,193 +447,449 @@ impl RnnModel {
     /// let weight_data: &'static [u8] = include_bytes!("/path/to/model/weights.rnn");
     /// let model = RnnModel::from_static_bytes(weight_data).expect("Corrupted model file");
     /// ```
-    pub fn from_static_bytes(bytes: &'static [u8]) -> Option<RnnModel> {
-        RnnModel::from_bytes_impl(to_i8(bytes), Cow::Borrowed)
+    pub fn from_static_bytes(bytes: &'static [u8]) -> Result<RnnModel, ModelError> {
+        RnnModel::from_file_data(bytes, &|xs, _| I8Weights::Static(xs))
     }
 
-    /// Reads an `RnnModel` from an array of bytes, in our new nnnoiseless format.
+    /// Reads an `RnnModel` from the text format used by [`rnnoise-nu`] and the models in
+    /// [`rnnoise-models`].
     ///
-    /// The format is simple: each NN layer is represented by an array of signed `i8`'s,
-    /// and these layers as simply concatenated.
+    /// This format consists of the header line `rnnoise-nu model file version 1`, followed by
+    /// whitespace-separated integers that are the same as the bytes of our legacy binary format
+    /// (except that the layer sizes can be larger than 127). The offsets in any [`ModelError`]
+    /// count these integers.
     ///
-    /// The format for a dense layer is
-    /// <nb_neurons> <nb_inputs> <activation>
-    /// <weights...>
-    /// <bias...>
-    /// where each of the <?> terms represents a single integer, and each of the <?...> terms
-    /// represents an array of integers of the appropriate length (`weights` has length
-    /// `nb_neurons * nb_inputs` and `bias` has length `nb_neurons`).
+    /// [`rnnoise-nu`]: https://github.com/GregorR/rnnoise-nu
+    /// [`rnnoise-models`]: https://github.com/GregorR/rnnoise-models
+    pub fn from_rnnoise_text(text: &str) -> Result<RnnModel, ModelError> {
+        let mut lines = text.lines();
+        match lines.next() {
+            Some(header) if header.trim() == RNNOISE_TEXT_HEADER => {}
+            _ => return Err(ModelError::InvalidTextHeader),
+        }
+
+        // Line numbers start at one, and we skipped the header.
+        model_reader::read_model(&mut TextReader::new(lines, 2))
+    }
+
+    /// Returns one of the models that are built into this crate, or `None` if there isn't a
+    /// built-in model called `name`.
     ///
-    /// The format for a GRU layer is
-    /// <nb_neurons> <nb_inputs> <activation>
-    /// <input_weights...>
-    /// <recurrent_weights...>
-    /// <bias...>
-    /// where `input_weights` and `recurrent_weights` have length `3 * nb_inputs * nb_neurons` each,
-    /// and `bias` has length `3 * nb_neurons`.
-    fn from_bytes_impl<'a>(
-        bytes: &'a [i8],
-        moo: fn(&'a [i8]) -> Cow<'static, [i8]>,
-    ) -> Option<RnnModel> {
-        let read_array = |bytes: &'a [i8], len: usize| -> Option<(Cow<'static, [i8]>, &[i8])> {
-            if bytes.len() >= len {
-                Some((moo(&bytes[..len]), &bytes[len..]))
-            } else {
-                None
-            }
-        };
+    /// The original RNNoise model, which is also the one returned by [`RnnModel::default`], is
+    /// always available under the name `rnnoise`. The others are only embedded in the binary if
+    /// the corresponding cargo feature is enabled:
+    ///
+    /// | Name | Feature | Description |
+    /// |------|---------|-------------|
+    /// | `rnnoise` | | The original RNNoise model. |
+    /// | `sh` | `model-sh` | The "somnolent hogwash" model from [`rnnoise-models`], for general speech and noise. |
+    ///
+    /// [`RnnModel::builtin_names`] lists the models that are available in this build.
+    ///
+    /// [`rnnoise-models`]: https://github.com/GregorR/rnnoise-models
+    pub fn builtin(name: &str) -> Option<RnnModel> {
+        BUILTIN_MODELS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, bytes)| RnnModel::from_static_bytes(bytes).unwrap())
+    }
 
-        fn unsigned(b: i8) -> Option<usize> {
-            if b >= 0 {
-                Some(b as usize)
-            } else {
-                None
-            }
+    /// Returns the names of the built-in models that are available in this build. See
+    /// [`RnnModel::builtin`].
+    pub fn builtin_names() -> impl Iterator<Item = &'static str> {
+        BUILTIN_MODELS.iter().map(|(name, _)| *name)
+    }
+
+    /// Reads an `RnnModel` from either a container or a legacy model file.
+    ///
+    /// `moo` takes the `i8` weights of a layer, along with their offset in `bytes`, and decides
+    /// how to store them.
+    fn from_file_data<'a>(bytes: &'a [u8], moo: StoreWeights<'a>) -> Result<RnnModel, ModelError> {
+        if container::is_container(bytes) {
+            let container = container::parse(bytes)?;
+            let payload = to_i8(container.payload);
+            let mut reader = BinaryReader::new(
+                payload,
+                container.payload_offset,
+                BinaryFormat::Container,
+                moo,
+            );
+            let mut model = match container.architecture {
+                Architecture::Rnnoise => model_reader::read_model(&mut reader)?,
+                Architecture::Graph => model_reader::read_graph(&mut reader)?,
+            };
+            model.metadata = container.metadata;
+            Ok(model)
+        } else {
+            let mut reader = BinaryReader::new(to_i8(bytes), 0, BinaryFormat::Legacy, moo);
+            model_reader::read_model(&mut reader)
         }
+    }
+}
 
-        fn act(x: i8) -> Option<Activation> {
-            match x {
-                0 => Some(Activation::Tanh),
-                1 => Some(Activation::Sigmoid),
-                2 => Some(Activation::Relu),
-                _ => None,
-            }
+impl RnnModel {
+    /// Creates a model with the layers of the original RNNoise network.
+    ///
+    /// `layers` must have the kinds given in `RNNOISE_LAYERS`. The sizes of the layers aren't
+    /// checked.
+    pub(crate) fn rnnoise(layers: Vec<Layer>) -> RnnModel {
+        let index = |name: &str| RNNOISE_LAYERS.iter().position(|l| l.0 == name).unwrap();
+        let nodes = layers
+            .into_iter()
+            .zip(RNNOISE_LAYERS.iter())
+            .map(|(layer, &(name, kind, inputs))| {
+                debug_assert_eq!(layer.kind(), kind);
+                let inputs = inputs
+                    .iter()
+                    .map(|&input| match input {
+                        FEATURES => Source::Features,
+                        _ => Source::Layer(index(input)),
+                    })
+                    .collect();
+                Node {
+                    name: name.to_owned(),
+                    layer,
+                    inputs,
+                }
+            })
+            .collect();
+        RnnModel {
+            nodes,
+            gains: index("denoise_output"),
+            vad: Some(index("vad_output")),
+            metadata: ModelMetadata::default(),
         }
+    }
 
-        let read_dense = |bytes: &'a [i8]| -> Option<(DenseLayer, &[i8])> {
-            if bytes.len() < 3 {
-                return None;
-            }
+    /// Returns true if this model has the same layers as the original RNNoise network (which
+    /// means that it can be stored in the legacy format).
+    fn is_rnnoise(&self) -> bool {
+        self.nodes.len() == RNNOISE_LAYERS.len()
+            && self.gains_layer() == "denoise_output"
+            && self.vad_layer() == Some("vad_output")
+            && self
+                .nodes
+                .iter()
+                .zip(RNNOISE_LAYERS.iter())
+                .all(|(node, &(name, kind, inputs))| {
+                    node.name == name
+                        && node.layer.kind() == kind
+                        && node
+                            .inputs
+                            .iter()
+                            .map(|&s| self.source_name(s))
+                            .eq(inputs.iter().copied())
+                })
+    }
 
-            let nb_inputs = unsigned(bytes[0])?;
-            let nb_neurons = unsigned(bytes[1])?;
-            let activation = act(bytes[2])?;
-            let (input_weights, bytes) = read_array(&bytes[3..], nb_neurons * nb_inputs)?;
-            let (bias, bytes) = read_array(bytes, nb_neurons)?;
+    pub(crate) fn source_name(&self, source: Source) -> &str {
+        match source {
+            Source::Features => FEATURES,
+            Source::Layer(i) => &self.nodes[i].name,
+        }
+    }
 
-            let layer = DenseLayer {
-                nb_inputs,
-                nb_neurons,
-                input_weights,
-                bias,
-                activation,
-            };
-            Some((layer, bytes))
-        };
+    /// Returns the number of outputs of `source`.
+    pub(crate) fn source_size(&self, source: Source) -> usize {
+        match source {
+            Source::Features => INPUT_SIZE,
+            Source::Layer(i) => self.nodes[i].layer.nb_neurons(),
+        }
+    }
 
-        let read_gru = |bytes: &'a [i8]| -> Option<(GruLayer, &[i8])> {
-            if bytes.len() < 3 {
-                return None;
-            }
+    /// Returns descriptions of all the layers in this model, in the order in which they are
+    /// evaluated.
+    pub fn layers(&self) -> Vec<LayerInfo> {
+        self.nodes
+            .iter()
+            .map(|node| {
+                let inputs = node
+                    .inputs
+                    .iter()
+                    .map(|&s| self.source_name(s).to_owned())
+                    .collect();
+                node.layer.info(node.name.clone(), inputs)
+            })
+            .collect()
+    }
 
-            let nb_inputs = unsigned(bytes[0])?;
-            let nb_neurons = unsigned(bytes[1])?;
-            let activation = act(bytes[2])?;
-            let (input_weights, bytes) = read_array(&bytes[3..], 3 * nb_neurons * nb_inputs)?;
-            let (recurrent_weights, bytes) = read_array(bytes, 3 * nb_neurons * nb_neurons)?;
-            let (bias, bytes) = read_array(bytes, 3 * nb_neurons)?;
+    /// Returns the name of the layer whose output is the gains of the frequency bands.
+    pub fn gains_layer(&self) -> &str {
+        &self.nodes[self.gains].name
+    }
 
-            let layer = GruLayer {
-                nb_inputs,
-                nb_neurons,
-                input_weights,
-                recurrent_weights,
-                bias,
-                activation,
-            };
-            Some((layer, bytes))
-        };
+    /// Returns the name of the layer whose output is the probability of voice activity, or
+    /// `None` if the model doesn't estimate voice activity.
+    ///
+    /// If there is no such layer, [`DenoiseState`](crate::DenoiseState) reports a voice activity
+    /// probability of one for every frame that isn't silent.
+    pub fn vad_layer(&self) -> Option<&str> {
+        self.vad.map(|i| self.nodes[i].name.as_str())
+    }
 
-        let (input_dense, bytes) = read_dense(bytes)?;
-        let (vad_gru, bytes) = read_gru(bytes)?;
-        let (noise_gru, bytes) = read_gru(bytes)?;
-        let (denoise_gru, bytes) = read_gru(bytes)?;
-        let (denoise_output, bytes) = read_dense(bytes)?;
-        let (vad_output, bytes) = read_dense(bytes)?;
+    /// Returns the total number of parameters (weights and biases) in this model.
+    pub fn nb_params(&self) -> usize {
+        self.layers().iter().map(|l| l.nb_params).sum()
+    }
 
-        if !bytes.is_empty() {
-            return None;
-        }
+    /// Returns this model's metadata.
+    pub fn metadata(&self) -> &ModelMetadata {
+        &self.metadata
+    }
 
-        // The input to the first layer must be of size 42, because that's how many features
-        // there are. The denoise output must be of size 22, and the vad output must be of size 1.
-        // Other than that, the output of one layer must match with the inputs of the following
-        // layer.
-        if input_dense.nb_inputs != 42
-            || denoise_output.nb_neurons != 22
-            || vad_output.nb_neurons != 1
-        {
-            return None;
-        }
-        if input_dense.nb_neurons != vad_gru.nb_inputs || vad_gru.nb_neurons != vad_output.nb_inputs
-        {
-            return None;
-        }
-        if 42 + input_dense.nb_neurons + vad_gru.nb_neurons != noise_gru.nb_inputs {
-            return None;
+    /// Returns this model's metadata, for modification.
+    pub fn metadata_mut(&mut self) -> &mut ModelMetadata {
+        &mut self.metadata
+    }
+
+    /// Serializes this model, in the format read by [`RnnModel::from_bytes`].
+    ///
+    /// The model is written in our container format, which includes the model's metadata and
+    /// a checksum. Older versions of `nnnoiseless` can't read this format; for them, use
+    /// [`RnnModel::to_legacy_bytes`].
+    ///
+    /// This returns [`Error::ModelFormat`] if the model can't be stored in the container format,
+    /// which happens if it has layers with more than 65535 inputs or neurons, or if its
+    /// [`extra`](ModelMetadata::extra) metadata uses one of the reserved keys.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        self.container_bytes().map_err(Error::ModelFormat)
+    }
+
+    /// Serializes this model in the legacy format, which consists of just the layers.
+    ///
+    /// This format can be read by all versions of `nnnoiseless`, but it doesn't include the
+    /// model's metadata. It also only supports models with the same layers as the original
+    /// RNNoise network, and it doesn't support layers with more than 127 inputs or neurons, or
+    /// weights with a precision other than [`WeightPrecision::I8`] (and the default scale). If
+    /// this model can't be written in the legacy format, this returns [`Error::ModelFormat`].
+    pub fn to_legacy_bytes(&self) -> Result<Vec<u8>, Error> {
+        if !self.is_rnnoise() {
+            return Err(Error::ModelFormat(
+                "model architecture can't be stored in the legacy model format",
+            ));
         }
-        if 42 + vad_gru.nb_neurons + noise_gru.nb_neurons != denoise_gru.nb_inputs {
-            return None;
+        let mut buf = Vec::new();
+        self.write_layers(&mut buf, BinaryFormat::Legacy)
+            .map_err(Error::ModelFormat)?;
+        Ok(buf)
+    }
+
+    /// Writes this model to `w`, in the format read by [`RnnModel::from_bytes`].
+    ///
+    /// This writes the same thing as [`RnnModel::to_bytes`]. If the model can't be written in
+    /// that format, this returns an error of kind
+    /// [`InvalidInput`](std::io::ErrorKind::InvalidInput).
+    ///
+    /// This is only available with the `std` feature; without it, use [`RnnModel::to_bytes`].
+    #[cfg(feature = "std")]
+    pub fn write_to<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        w.write_all(&self.to_bytes().map_err(invalid_input)?)
+    }
+
+    /// Writes this model to `w`, in the legacy format that consists of just the layers.
+    ///
+    /// This writes the same thing as [`RnnModel::to_legacy_bytes`]. If the model can't be
+    /// written in that format, this returns an error of kind
+    /// [`InvalidInput`](std::io::ErrorKind::InvalidInput).
+    ///
+    /// This is only available with the `std` feature; without it, use
+    /// [`RnnModel::to_legacy_bytes`].
+    #[cfg(feature = "std")]
+    pub fn write_legacy_to<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        w.write_all(&self.to_legacy_bytes().map_err(invalid_input)?)
+    }
+
+    /// Serializes this model in the container format.
+    fn container_bytes(&self) -> Result<Vec<u8>, container::WriteError> {
+        let mut payload = Vec::new();
+        if self.is_rnnoise() {
+            self.write_layers(&mut payload, BinaryFormat::Container)?;
+            container::write(Architecture::Rnnoise, &self.metadata, &payload)
+        } else {
+            self.write_graph(&mut payload)?;
+            container::write(Architecture::Graph, &self.metadata, &payload)
         }
-        if denoise_gru.nb_neurons != denoise_output.nb_inputs {
-            return None;
+    }
+
+    fn write_layers(
+        &self,
+        buf: &mut Vec<u8>,
+        format: BinaryFormat,
+    ) -> Result<(), container::WriteError> {
+        for node in &self.nodes {
+            node.layer.write_to(buf, format)?;
         }
+        Ok(())
+    }
 
-        Some(RnnModel {
-            input_dense,
-            vad_gru,
-            noise_gru,
-            denoise_gru,
-            denoise_output,
-            vad_output,
-        })
+    fn write_graph(&self, buf: &mut Vec<u8>) -> Result<(), container::WriteError> {
+        container::write_len(buf, self.nodes.len())?;
+        for node in &self.nodes {
+            container::write_string(buf, &node.name)?;
+            buf.push(node.layer.kind() as u8);
+            container::write_len(buf, node.inputs.len())?;
+            for &input in &node.inputs {
+                container::write_string(buf, self.source_name(input))?;
+            }
+            node.layer.write_to(buf, BinaryFormat::Container)?;
+        }
+        container::write_string(buf, self.gains_layer())?;
+        container::write_string(buf, self.vad_layer().unwrap_or(""))
     }
 }
 
+#[cfg(feature = "std")]
+fn invalid_input(e: Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+}
+
+/// The original RNNoise model, which is the default.
+const DEFAULT_MODEL: &[u8] = include_bytes!("weights.rnn");
+
+/// The models returned by [`RnnModel::builtin`], along with their names.
+const BUILTIN_MODELS: &[(&str, &[u8])] = &[
+    ("rnnoise", DEFAULT_MODEL),
+    #[cfg(feature = "model-sh")]
+    ("sh", include_bytes!("models/sh.rnn")),
+];
+
 impl Default for RnnModel {
     fn default() -> RnnModel {
-        let bytes: &'static [u8] = include_bytes!("weights.rnn");
-        RnnModel::from_static_bytes(bytes).unwrap()
+        RnnModel::from_static_bytes(DEFAULT_MODEL).unwrap()
+    }
+}
+
+impl Layer {
+    pub(crate) fn kind(&self) -> LayerKind {
+        match self {
+            Layer::Dense(_) => LayerKind::Dense,
+            Layer::Gru(_) => LayerKind::Gru,
+        }
+    }
+
+    pub(crate) fn nb_inputs(&self) -> usize {
+        match self {
+            Layer::Dense(l) => l.nb_inputs,
+            Layer::Gru(l) => l.nb_inputs,
+        }
+    }
+
+    pub(crate) fn nb_neurons(&self) -> usize {
+        match self {
+            Layer::Dense(l) => l.nb_neurons,
+            Layer::Gru(l) => l.nb_neurons,
+        }
+    }
+
+    fn info(&self, name: String, inputs: Vec<String>) -> LayerInfo {
+        match self {
+            Layer::Dense(l) => l.info(name, inputs),
+            Layer::Gru(l) => l.info(name, inputs),
+        }
+    }
+
+    fn write_to(
+        &self,
+        buf: &mut Vec<u8>,
+        format: BinaryFormat,
+    ) -> Result<(), container::WriteError> {
+        match self {
+            Layer::Dense(l) => l.write_to(buf, format),
+            Layer::Gru(l) => l.write_to(buf, format),
+        }
     }
 }
 
 impl DenseLayer {
+    fn info(&self, name: String, inputs: Vec<String>) -> LayerInfo {
+        LayerInfo {
+            name,
+            kind: LayerKind::Dense,
+            inputs,
+            nb_inputs: self.nb_inputs,
+            nb_neurons: self.nb_neurons,
+            activation: self.activation,
+            precision: self.input_weights.precision(),
+            nb_params: self.input_weights.len() + self.bias.len(),
+            weights: WeightStats::new(&[&self.input_weights, &self.bias], self.scale),
+        }
+    }
+
+    fn write_to(
+        &self,
+        buf: &mut Vec<u8>,
+        format: BinaryFormat,
+    ) -> Result<(), container::WriteError> {
+        write_header(
+            buf,
+            self.nb_inputs,
+            self.nb_neurons,
+            self.activation,
+            &self.input_weights,
+            self.scale,
+            format,
+        )?;
+        self.input_weights.write_to(buf);
+        self.bias.write_to(buf);
+        Ok(())
+    }
+
     fn matrix(&self) -> SubMatrix {
         SubMatrix {
-            data: self.input_weights.as_ref(),
+            data: &self.input_weights,
             stride: self.nb_neurons,
             offset: 0,
         }
     }
 
-    fn compute(&self, output: &mut [f32], input: &[f32]) {
-        copy_i8(output, &self.bias[..]);
-        self.matrix().mul_add(output, input);
-
-        match self.activation {
-            Activation::Sigmoid => {
-                for out in output.iter_mut() {
-                    *out = sigmoid_approx(*out * WEIGHTS_SCALE);
-                }
-            }
-            Activation::Tanh => {
-                for out in output.iter_mut() {
-                    *out = tansig_approx(*out * WEIGHTS_SCALE);
-                }
-            }
-            Activation::Relu => {
-                for out in output.iter_mut() {
-                    *out = relu(*out * WEIGHTS_SCALE);
-                }
-            }
-        }
+    fn compute(&self, isa: Isa, output: &mut [f32], input: &[f32]) {
+        self.bias.copy_to(output, 0);
+        self.matrix().mul_add(isa, output, input);
+        activate(isa, self.activation, output, self.scale);
     }
 }
 
 impl GruLayer {
+    fn info(&self, name: String, inputs: Vec<String>) -> LayerInfo {
+        LayerInfo {
+            name,
+            kind: LayerKind::Gru,
+            inputs,
+            nb_inputs: self.nb_inputs,
+            nb_neurons: self.nb_neurons,
+            activation: self.activation,
+            precision: self.input_weights.precision(),
+            nb_params: self.input_weights.len() + self.recurrent_weights.len() + self.bias.len(),
+            weights: WeightStats::new(
+                &[&self.input_weights, &self.recurrent_weights, &self.bias],
+                self.scale,
+            ),
+        }
+    }
+
+    fn write_to(
+        &self,
+        buf: &mut Vec<u8>,
+        format: BinaryFormat,
+    ) -> Result<(), container::WriteError> {
+        write_header(
+            buf,
+            self.nb_inputs,
+            self.nb_neurons,
+            self.activation,
+            &self.input_weights,
+            self.scale,
+            format,
+        )?;
+        self.input_weights.write_to(buf);
+        self.recurrent_weights.write_to(buf);
+        self.bias.write_to(buf);
+        Ok(())
+    }
+
     fn input_submatrix(&self, offset: usize) -> SubMatrix {
         SubMatrix {
-            data: self.input_weights.as_ref(),
+            data: &self.input_weights,
             stride: self.nb_neurons * 3,
             offset,
         }
@@ -283,103 +897,206 @@ impl GruLayer {
 
     fn rec_submatrix(&self, offset: usize) -> SubMatrix {
         SubMatrix {
-            data: self.recurrent_weights.as_ref(),
+            data: &self.recurrent_weights,
             stride: self.nb_neurons * 3,
             offset,
         }
     }
 
-    fn compute(&self, state: &mut [f32], input: &[f32]) {
-        let mut z = [0.0; MAX_NEURONS];
-        let mut r = [0.0; MAX_NEURONS];
-        let mut h = [0.0; MAX_NEURONS];
+    /// Updates `state` with the next `input`. `scratch` is a buffer of length at least
+    /// `3 * nb_neurons`, for temporary storage.
+    fn compute(&self, isa: Isa, state: &mut [f32], input: &[f32], scratch: &mut [f32]) {
         let n = self.nb_neurons;
+        let (z, rest) = scratch.split_at_mut(n);
+        let (r, h) = rest.split_at_mut(n);
+        let scale = self.scale;
 
         // Compute update gate.
-        copy_i8(&mut z[0..n], &self.bias[0..n]);
-        self.input_submatrix(0).mul_add(&mut z[0..n], input);
-        self.rec_submatrix(0).mul_add(&mut z[0..n], &state[..]);
-        for z in z[0..n].iter_mut() {
-            *z = sigmoid_approx(WEIGHTS_SCALE * *z);
-        }
+        self.bias.copy_to(&mut z[0..n], 0);
+        self.input_submatrix(0).mul_add(isa, &mut z[0..n], input);
+        self.rec_submatrix(0).mul_add(isa, &mut z[0..n], &state[..]);
+        simd::sigmoid(isa, &mut z[0..n], scale);
 
         // Compute reset gate.
-        copy_i8(&mut r[0..n], &self.bias[n..(2 * n)]);
-        self.input_submatrix(n).mul_add(&mut r[0..n], input);
-        self.rec_submatrix(n).mul_add(&mut r[0..n], &state[..]);
+        self.bias.copy_to(&mut r[0..n], n);
+        self.input_submatrix(n).mul_add(isa, &mut r[0..n], input);
+        self.rec_submatrix(n).mul_add(isa, &mut r[0..n], &state[..]);
+        simd::sigmoid(isa, &mut r[0..n], scale);
         for (out, &s) in r[0..n].iter_mut().zip(&state[..]) {
-            *out = s * sigmoid_approx(WEIGHTS_SCALE * *out);
+            *out *= s;
         }
 
         // Compute output.
-        copy_i8(&mut h[0..n], &self.bias[(2 * n)..]);
-        self.input_submatrix(2 * n).mul_add(&mut h[0..n], input);
-        self.rec_submatrix(2 * n).mul_add(&mut h[0..n], &r[0..n]);
+        self.bias.copy_to(&mut h[0..n], 2 * n);
+        self.input_submatrix(2 * n)
+            .mul_add(isa, &mut h[0..n], input);
+        self.rec_submatrix(2 * n)
+            .mul_add(isa, &mut h[0..n], &r[0..n]);
+        activate(isa, self.activation, &mut h[0..n], scale);
 
         for (s, &z, &h) in zip3(state, &z[0..n], &h[0..n]) {
-            let h = match self.activation {
-                Activation::Sigmoid => sigmoid_approx(WEIGHTS_SCALE * h),
-                Activation::Tanh => tansig_approx(WEIGHTS_SCALE * h),
-                Activation::Relu => relu(WEIGHTS_SCALE * h),
-            };
             *s = z * *s + (1.0 - z) * h;
         }
     }
 }
 
+/// Replaces each `x` in `xs` by `activation(scale * x)`.
+fn activate(isa: Isa, activation: Activation, xs: &mut [f32], scale: f32) {
+    match activation {
+        Activation::Sigmoid => simd::sigmoid(isa, xs, scale),
+        Activation::Tanh => simd::tansig(isa, xs, scale),
+        Activation::Relu => {
+            for x in xs.iter_mut() {
+                *x = relu(scale * *x);
+            }
+        }
+    }
+}
+
+/// A model that is used by a [`DenoiseState`](crate::DenoiseState), which can either own it,
+/// borrow it, or share it with others.
+///
+/// You don't usually need to name this type: methods that take a `ModelRef` accept anything
+/// that can be converted into one, which is an [`RnnModel`], a `&RnnModel` or an
+/// `Arc<RnnModel>`. The last of these is the most flexible way to share a model: the states
+/// using it don't borrow anything (so they can be `'static`), and cloning them doesn't clone the
+/// model.
+#[derive(Clone)]
+pub struct ModelRef<'model>(ModelRefInner<'model>);
+
+#[derive(Clone)]
+enum ModelRefInner<'model> {
+    Owned(RnnModel),
+    Borrowed(&'model RnnModel),
+    Shared(Arc<RnnModel>),
+}
+
+impl Deref for ModelRef<'_> {
+    type Target = RnnModel;
+
+    fn deref(&self) -> &RnnModel {
+        match &self.0 {
+            ModelRefInner::Owned(model) => model,
+            ModelRefInner::Borrowed(model) => model,
+            ModelRefInner::Shared(model) => model,
+        }
+    }
+}
+
+impl From<RnnModel> for ModelRef<'_> {
+    fn from(model: RnnModel) -> Self {
+        ModelRef(ModelRefInner::Owned(model))
+    }
+}
+
+impl<'model> From<&'model RnnModel> for ModelRef<'model> {
+    fn from(model: &'model RnnModel) -> Self {
+        ModelRef(ModelRefInner::Borrowed(model))
+    }
+}
+
+impl From<Arc<RnnModel>> for ModelRef<'_> {
+    fn from(model: Arc<RnnModel>) -> Self {
+        ModelRef(ModelRefInner::Shared(model))
+    }
+}
+
 impl<'model> RnnState<'model> {
-    pub(crate) fn new(model: Cow<'model, RnnModel>) -> RnnState<'model> {
-        let vad_gru_state = vec![0.0f32; model.vad_gru.nb_neurons];
-        let noise_gru_state = vec![0.0f32; model.noise_gru.nb_neurons];
-        let denoise_gru_state = vec![0.0f32; model.denoise_gru.nb_neurons];
+    pub(crate) fn model(&self) -> &RnnModel {
+        &self.model
+    }
+
+    pub(crate) fn new(model: ModelRef<'model>) -> RnnState<'model> {
+        let outputs = model
+            .nodes
+            .iter()
+            .map(|node| vec![0.0f32; node.layer.nb_neurons()])
+            .collect();
+        let max_inputs = model
+            .nodes
+            .iter()
+            .map(|node| node.layer.nb_inputs())
+            .max()
+            .unwrap_or(0);
+        let max_gru_neurons = model
+            .nodes
+            .iter()
+            .filter(|node| node.layer.kind() == LayerKind::Gru)
+            .map(|node| node.layer.nb_neurons())
+            .max()
+            .unwrap_or(0);
         RnnState {
             model,
-            vad_gru_state,
-            noise_gru_state,
-            denoise_gru_state,
+            outputs,
+            input_buf: vec![0.0; max_inputs],
+            gru_buf: vec![0.0; 3 * max_gru_neurons],
+            isa: Isa::detect(),
+        }
+    }
+
+    /// Forgets the state of the GRU layers.
+    pub(crate) fn reset(&mut self) {
+        for output in &mut self.outputs {
+            output.iter_mut().for_each(|x| *x = 0.0);
         }
     }
 
+    /// Returns the outputs of all the layers, which include the state of the GRU layers.
+    pub(crate) fn snapshot(&self) -> Vec<Vec<f32>> {
+        self.outputs.clone()
+    }
+
+    /// Restores the layer outputs saved by [`RnnState::snapshot`].
+    pub(crate) fn restore(&mut self, outputs: &[Vec<f32>]) -> Result<(), Error> {
+        let fits = outputs.len() == self.outputs.len()
+            && outputs
+                .iter()
+                .zip(&self.outputs)
+                .all(|(x, y)| x.len() == y.len());
+        if !fits {
+            return Err(Error::InvalidSnapshot);
+        }
+        for (dst, src) in self.outputs.iter_mut().zip(outputs) {
+            dst.copy_from_slice(src);
+        }
+        Ok(())
+    }
+
+    /// Runs the model on a frame of `input` features, putting the band gains in `gains` and the
+    /// voice activity probability in `vad`.
     pub fn compute(&mut self, gains: &mut [f32], vad: &mut [f32], input: &[f32]) {
         assert_eq!(input.len(), INPUT_SIZE);
 
-        let mut buf = [0.0; MAX_NEURONS * 3];
-        let mut denoise_buf = [0.0; MAX_NEURONS * 3];
         let model = &self.model;
+        for (i, node) in model.nodes.iter().enumerate() {
+            let mut len = 0;
+            for &source in &node.inputs {
+                let src = match source {
+                    Source::Features => input,
+                    Source::Layer(j) => &self.outputs[j][..],
+                };
+                copy(&mut self.input_buf[len..(len + src.len())], src);
+                len += src.len();
+            }
+            let input = &self.input_buf[..len];
 
-        let vad_gru_state = &mut self.vad_gru_state[..];
-        let noise_gru_state = &mut self.noise_gru_state[..];
-        let denoise_gru_state = &mut self.denoise_gru_state[..];
-        model
-            .input_dense
-            .compute(&mut buf[0..model.input_dense.nb_neurons], input);
-        model
-            .vad_gru
-            .compute(vad_gru_state, &buf[0..model.input_dense.nb_neurons]);
-        model.vad_output.compute(vad, vad_gru_state);
-
-        copy(&mut buf[model.input_dense.nb_neurons..], vad_gru_state);
-        copy(
-            &mut buf[(model.input_dense.nb_neurons + model.vad_gru.nb_neurons)..],
-            input,
-        );
-        model.noise_gru.compute(noise_gru_state, &buf);
+            match &node.layer {
+                Layer::Dense(l) => l.compute(self.isa, &mut self.outputs[i], input),
+                Layer::Gru(l) => {
+                    l.compute(self.isa, &mut self.outputs[i], input, &mut self.gru_buf)
+                }
+            }
+        }
 
-        copy(&mut denoise_buf, vad_gru_state);
-        copy(
-            &mut denoise_buf[model.vad_gru.nb_neurons..],
-            noise_gru_state,
-        );
-        copy(
-            &mut denoise_buf[(model.vad_gru.nb_neurons + model.noise_gru.nb_neurons)..],
-            input,
-        );
-        model.denoise_gru.compute(denoise_gru_state, &denoise_buf);
-        model.denoise_output.compute(gains, denoise_gru_state);
+        copy(gains, &self.outputs[model.gains]);
+        vad[0] = match model.vad {
+            Some(i) => self.outputs[i][0],
+            None => 1.0,
+        };
     }
 }
 
-const INPUT_SIZE: usize = 42;
+pub(crate) const INPUT_SIZE: usize = 42;
 
 fn copy(dst: &mut [f32], src: &[f32]) {
     for (x, y) in dst.iter_mut().zip(src) {
@@ -387,24 +1104,688 @@ fn copy(dst: &mut [f32], src: &[f32]) {
     }
 }
 
-fn copy_i8(dst: &mut [f32], src: &[i8]) {
-    for (x, y) in dst.iter_mut().zip(src) {
-        *x = *y as f32;
-    }
-}
-
 struct SubMatrix<'a> {
-    data: &'a [i8],
+    data: &'a Weights,
     stride: usize,
     offset: usize,
 }
 
 impl<'a> SubMatrix<'a> {
-    fn mul_add(&self, output: &mut [f32], input: &[f32]) {
-        for (col, input) in self.data.chunks_exact(self.stride).zip(input) {
-            for (&x, out) in col[self.offset..].iter().zip(&mut *output) {
-                *out += x as f32 * input;
+    fn mul_add(&self, isa: Isa, output: &mut [f32], input: &[f32]) {
+        let (stride, offset) = (self.stride, self.offset);
+        match self.data {
+            Weights::I8(data) => simd::mul_add(isa, output, data, stride, offset, input),
+            Weights::I16(data) => simd::mul_add(isa, output, data, stride, offset, input),
+            Weights::F32(data) => simd::mul_add(isa, output, data, stride, offset, input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model_bytes() -> Vec<u8> {
+        include_bytes!("weights.rnn").to_vec()
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = model_bytes();
+        let mut model = RnnModel::from_bytes(&bytes).unwrap();
+        assert_eq!(model.to_legacy_bytes().unwrap(), bytes);
+
+        model.metadata_mut().name = Some("default".to_owned());
+        model
+            .metadata_mut()
+            .extra
+            .insert("author".to_owned(), "someone".to_owned());
+        let written = model.to_bytes().unwrap();
+        let mut read = RnnModel::from_bytes(&written).unwrap();
+        assert_eq!(read.metadata(), model.metadata());
+        assert_eq!(read.to_bytes().unwrap(), written);
+
+        #[cfg(feature = "std")]
+        {
+            let mut legacy = Vec::new();
+            read.write_legacy_to(&mut legacy).unwrap();
+            assert_eq!(legacy, bytes);
+            let mut written_to = Vec::new();
+            read.write_to(&mut written_to).unwrap();
+            assert_eq!(written_to, written);
+        }
+        read.metadata_mut().name = None;
+        read.metadata_mut().extra.clear();
+        assert_eq!(read.metadata(), &ModelMetadata::default());
+
+        // Extra metadata with the same key as a typed field wouldn't survive the round trip.
+        read.metadata_mut()
+            .extra
+            .insert("name".to_owned(), "extra".to_owned());
+        assert!(matches!(read.to_bytes(), Err(Error::ModelFormat(_))));
+        #[cfg(feature = "std")]
+        assert!(read.write_to(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn shared_bytes() {
+        // Both the legacy format and the container format can be shared.
+        for bytes in [model_bytes(), RnnModel::default().to_bytes().unwrap()] {
+            let expected = RnnModel::from_bytes(&bytes).unwrap().to_bytes().unwrap();
+            let data: Arc<[u8]> = bytes.into();
+            let model = RnnModel::from_shared_bytes(Arc::clone(&data)).unwrap();
+            assert_eq!(model.to_bytes().unwrap(), expected);
+
+            let weights = match &model.nodes[0].layer {
+                Layer::Dense(l) => &l.input_weights,
+                Layer::Gru(_) => unreachable!(),
+            };
+            assert!(matches!(weights, Weights::I8(I8Weights::Shared { .. })));
+            assert!(data.as_ptr_range().contains(&(weights_ptr(weights))));
+
+            // The data is shared between clones, and freed along with the last one.
+            let clone = model.clone();
+            drop(model);
+            assert_eq!(clone.to_bytes().unwrap(), expected);
+            drop(clone);
+            assert_eq!(Arc::strong_count(&data), 1);
+        }
+    }
+
+    fn weights_ptr(weights: &Weights) -> *const u8 {
+        match weights {
+            Weights::I8(w) => w.as_ptr() as *const u8,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn from_reader() {
+        let bytes = RnnModel::default().to_bytes().unwrap();
+        let model = RnnModel::from_reader(&bytes[..]).unwrap();
+        assert_eq!(model.to_bytes().unwrap(), bytes);
+
+        let err = RnnModel::from_reader(&bytes[..10]).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(matches!(
+            err.get_ref().unwrap().downcast_ref::<ModelError>(),
+            Some(ModelError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn container_errors() {
+        let mut bytes = RnnModel::default().to_bytes().unwrap();
+        let len = bytes.len();
+
+        // An error in the layer data should be reported with its offset in the container, but
+        // only once the checksum matches.
+        // The layers are the same as in the legacy format, except that each of the six layer
+        // headers has seven extra bytes (two for the wider sizes, one for the precision and four
+        // for the scale).
+        let layer_start = len - 4 - model_bytes().len() - 7 * 6;
+        bytes[layer_start + 4] = 7;
+        assert!(matches!(
+            RnnModel::from_bytes(&bytes),
+            Err(ModelError::ChecksumMismatch { .. })
+        ));
+        let checksum = container::crc32(&bytes[..(len - 4)]);
+        bytes[(len - 4)..].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            RnnModel::from_bytes(&bytes).err(),
+            Some(ModelError::InvalidActivation {
+                layer: "input_dense".to_owned(),
+                offset: layer_start + 4,
+                value: 7
+            })
+        );
+
+        bytes[layer_start + 4] = 0;
+        bytes[layer_start + 5] = 3;
+        let checksum = container::crc32(&bytes[..(len - 4)]);
+        bytes[(len - 4)..].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            RnnModel::from_bytes(&bytes).err(),
+            Some(ModelError::InvalidPrecision {
+                layer: "input_dense".to_owned(),
+                offset: layer_start + 5,
+                value: 3
+            })
+        );
+
+        // A layer with no neurons. (The sizes are the first four bytes of the header.)
+        bytes[layer_start + 5] = 0;
+        bytes[(layer_start + 2)..(layer_start + 4)].copy_from_slice(&[0, 0]);
+        let checksum = container::crc32(&bytes[..(len - 4)]);
+        bytes[(len - 4)..].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            RnnModel::from_bytes(&bytes).err(),
+            Some(ModelError::InvalidDimension {
+                layer: "input_dense".to_owned(),
+                offset: layer_start + 2,
+                value: 0
+            })
+        );
+    }
+
+    /// Returns a copy of `model` in which the weights of every layer have been converted by `f`,
+    /// and the scales have been set to `scale`.
+    fn convert_weights(model: &RnnModel, f: fn(&Weights) -> Weights, scale: f32) -> RnnModel {
+        let mut ret = model.clone();
+        for node in &mut ret.nodes {
+            match &mut node.layer {
+                Layer::Dense(l) => {
+                    l.bias = f(&l.bias);
+                    l.input_weights = f(&l.input_weights);
+                    l.scale = scale;
+                }
+                Layer::Gru(l) => {
+                    l.bias = f(&l.bias);
+                    l.input_weights = f(&l.input_weights);
+                    l.recurrent_weights = f(&l.recurrent_weights);
+                    l.scale = scale;
+                }
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn precisions() {
+        let model = RnnModel::default();
+        // These conversions are exact, and they only change the scales by powers of two, so the
+        // converted models should give exactly the same output as the original.
+        let i16_model = convert_weights(
+            &model,
+            |w| Weights::I16(w.values().map(|x| x as i16 * 256).collect()),
+            WEIGHTS_SCALE / 256.0,
+        );
+        let f32_model = convert_weights(
+            &model,
+            |w| Weights::F32(w.values().map(|x| x * WEIGHTS_SCALE).collect()),
+            1.0,
+        );
+
+        assert!(f32_model.to_legacy_bytes().is_err());
+        let i16_model = RnnModel::from_bytes(&i16_model.to_bytes().unwrap()).unwrap();
+        let f32_model = RnnModel::from_bytes(&f32_model.to_bytes().unwrap()).unwrap();
+        assert_eq!(i16_model.layers()[0].precision, WeightPrecision::I16);
+        assert_eq!(f32_model.layers()[0].precision, WeightPrecision::F32);
+        assert_eq!(f32_model.layers()[0].weights, model.layers()[0].weights);
+
+        let mut states: Vec<_> = [model, i16_model, f32_model]
+            .iter()
+            .map(|m| RnnState::new(m.clone().into()))
+            .collect();
+        for frame in 0..20 {
+            let input: Vec<f32> = (0..INPUT_SIZE)
+                .map(|i| ((frame * INPUT_SIZE + i) as f32).sin())
+                .collect();
+            let outputs: Vec<_> = states
+                .iter_mut()
+                .map(|state| {
+                    let mut gains = [0.0; crate::NB_BANDS];
+                    let mut vad = [0.0];
+                    state.compute(&mut gains, &mut vad, &input);
+                    (gains, vad)
+                })
+                .collect();
+            assert_eq!(outputs[0], outputs[1]);
+            assert_eq!(outputs[0], outputs[2]);
+        }
+
+        // Put a NaN in the first weight of the first layer. In a model with `f32` weights, the
+        // layers take up four bytes per parameter plus ten bytes per header.
+        let f32_model = convert_weights(
+            &RnnModel::default(),
+            |w| Weights::F32(w.values().collect()),
+            1.0,
+        );
+        let mut bytes = f32_model.to_bytes().unwrap();
+        let len = bytes.len();
+        let first_weight = len - 4 - (4 * f32_model.nb_params() + 10 * 6) + 10;
+        bytes[first_weight..(first_weight + 4)].copy_from_slice(&f32::NAN.to_le_bytes());
+        let checksum = container::crc32(&bytes[..(len - 4)]);
+        bytes[(len - 4)..].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            RnnModel::from_bytes(&bytes).err(),
+            Some(ModelError::NonFiniteWeight {
+                layer: "input_dense".to_owned(),
+                offset: first_weight,
+            })
+        );
+    }
+
+    #[test]
+    fn instruction_sets() {
+        // Every instruction set gives exactly the same outputs as the scalar code.
+        let model = RnnModel::default();
+        let mut scalar = RnnState::new((&model).into());
+        scalar.isa = Isa::Scalar;
+        let mut states: Vec<_> = Isa::supported()
+            .map(|isa| {
+                let mut state = RnnState::new((&model).into());
+                state.isa = isa;
+                state
+            })
+            .collect();
+        for frame in 0..50 {
+            let input: Vec<f32> = (0..INPUT_SIZE)
+                .map(|i| 4.0 * ((frame * INPUT_SIZE + i) as f32).sin())
+                .collect();
+            let mut expected = ([0.0; crate::NB_BANDS], [0.0]);
+            scalar.compute(&mut expected.0, &mut expected.1, &input);
+            for state in &mut states {
+                let mut gains = [0.0; crate::NB_BANDS];
+                let mut vad = [0.0];
+                state.compute(&mut gains, &mut vad, &input);
+                assert_eq!((gains, vad), expected, "{:?}", state.isa);
             }
         }
     }
+
+    #[test]
+    fn builtin() {
+        let model = RnnModel::builtin("rnnoise").unwrap();
+        assert_eq!(
+            model.to_bytes().unwrap(),
+            RnnModel::default().to_bytes().unwrap()
+        );
+        assert!(RnnModel::builtin("nonexistent").is_none());
+        assert!(RnnModel::builtin_names().any(|name| name == "rnnoise"));
+
+        // The built-in models should have the same weights as the ones they were converted from.
+        // (The text format has no metadata, so only compare the layers.)
+        let sh = RnnModel::builtin("sh");
+        if cfg!(feature = "model-sh") {
+            let text = include_str!("../test_data/sh.rnnn");
+            let expected = RnnModel::from_rnnoise_text(text)
+                .unwrap()
+                .to_bytes()
+                .unwrap();
+            let mut sh = sh.unwrap();
+            *sh.metadata_mut() = ModelMetadata::default();
+            assert_eq!(sh.to_bytes().unwrap(), expected);
+        } else {
+            assert!(sh.is_none());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn rnnoise_text() {
+        let text = include_str!("../test_data/sh.rnnn");
+        let model = RnnModel::from_rnnoise_text(text).unwrap();
+        let mut bytes = Vec::new();
+        model.write_legacy_to(&mut bytes).unwrap();
+        let expected: Vec<u8> = text
+            .lines()
+            .skip(1)
+            .flat_map(|line| line.split_whitespace())
+            .map(|w| w.parse::<i8>().unwrap() as u8)
+            .collect();
+        assert_eq!(bytes, expected);
+
+        assert_eq!(
+            RnnModel::from_rnnoise_text("42 24 0").err(),
+            Some(ModelError::InvalidTextHeader)
+        );
+        assert_eq!(
+            RnnModel::from_rnnoise_text("rnnoise-nu model file version 1\n42 24 0\n1 2 300").err(),
+            Some(ModelError::InvalidTextNumber {
+                line: 3,
+                word: "300".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn layers() {
+        let model = RnnModel::default();
+        let layers = model.layers();
+        let names: Vec<_> = layers.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "input_dense",
+                "vad_gru",
+                "noise_gru",
+                "denoise_gru",
+                "denoise_output",
+                "vad_output"
+            ]
+        );
+        assert_eq!(layers[0].kind, LayerKind::Dense);
+        assert_eq!(layers[0].nb_inputs, INPUT_SIZE);
+        assert_eq!(layers[2].kind, LayerKind::Gru);
+        assert_eq!(layers[4].nb_neurons, crate::NB_BANDS);
+        assert_eq!(layers[2].inputs, ["input_dense", "vad_gru", "features"]);
+        assert_eq!(model.gains_layer(), "denoise_output");
+        assert_eq!(model.vad_layer(), Some("vad_output"));
+
+        // Apart from the three-byte layer headers, every byte in the model file is a parameter.
+        assert_eq!(model.nb_params(), model_bytes().len() - 3 * layers.len());
+        for layer in &layers {
+            let w = &layer.weights;
+            assert!(w.min <= w.mean && w.mean <= w.max);
+            assert!(w.std_dev >= 0.0);
+            assert!(w.zeros <= layer.nb_params);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = model_bytes();
+        assert_eq!(
+            RnnModel::from_bytes(&bytes[..2]).err(),
+            Some(ModelError::Truncated {
+                layer: "input_dense".to_owned(),
+                offset: 2
+            })
+        );
+        assert_eq!(
+            RnnModel::from_bytes(&bytes[..(bytes.len() - 1)]).err(),
+            Some(ModelError::Truncated {
+                layer: "vad_output".to_owned(),
+                offset: bytes.len() - 1,
+            })
+        );
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut bytes = model_bytes();
+        let len = bytes.len();
+        bytes.extend_from_slice(&[0, 0, 0]);
+        assert_eq!(
+            RnnModel::from_bytes(&bytes).err(),
+            Some(ModelError::TrailingBytes {
+                offset: len,
+                len: 3
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_header() {
+        let mut bytes = model_bytes();
+        bytes[1] = -1i8 as u8;
+        assert_eq!(
+            RnnModel::from_bytes(&bytes).err(),
+            Some(ModelError::InvalidDimension {
+                layer: "input_dense".to_owned(),
+                offset: 1,
+                value: -1
+            })
+        );
+
+        let mut bytes = model_bytes();
+        bytes[2] = 7;
+        assert_eq!(
+            RnnModel::from_bytes(&bytes).err(),
+            Some(ModelError::InvalidActivation {
+                layer: "input_dense".to_owned(),
+                offset: 2,
+                value: 7
+            })
+        );
+    }
+
+    #[test]
+    fn huge_layer() {
+        // Give the VAD GRU layer 65535 inputs and neurons. Its number of weights overflows a
+        // 32-bit `usize`, and is far more than the file contains on 64-bit targets; either way,
+        // reading it must fail without panicking.
+        let model = RnnModel::default();
+        let mut bytes = model.to_bytes().unwrap();
+        let len = bytes.len();
+        let start = len - 4 - (model.nb_params() + 10 * 6) + 10 + model.layers()[0].nb_params;
+        bytes[start..(start + 4)].copy_from_slice(&[0xff; 4]);
+        let checksum = container::crc32(&bytes[..(len - 4)]);
+        bytes[(len - 4)..].copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(
+            RnnModel::from_bytes(&bytes),
+            Err(ModelError::Truncated { layer, .. } | ModelError::InvalidDimension { layer, .. })
+                if layer == "vad_gru"
+        ));
+
+        let text = "rnnoise-nu model file version 1\n2147483647 2147483647 0\n";
+        assert!(RnnModel::from_rnnoise_text(text).is_err());
+    }
+
+    #[test]
+    fn dimension_mismatch() {
+        // Find the start of the noise GRU layer, and give it one input too few (removing the
+        // corresponding weights, so that the layer still has the right length).
+        let layers = RnnModel::default().layers();
+        let start = 3 + layers[0].nb_params + 3 + layers[1].nb_params;
+        let mut bytes = model_bytes();
+        let nb_inputs = bytes[start] as usize;
+        let nb_neurons = bytes[start + 1] as usize;
+        bytes[start] -= 1;
+        bytes.drain((start + 3)..(start + 3 + 3 * nb_neurons));
+        assert_eq!(
+            RnnModel::from_bytes(&bytes).err(),
+            Some(ModelError::DimensionMismatch {
+                field: "noise_gru.nb_inputs".to_owned(),
+                offset: start,
+                expected: nb_inputs,
+                actual: nb_inputs - 1,
+            })
+        );
+    }
+
+    /// Builds a layer with some arbitrary small weights.
+    fn random_layer(
+        seed: &mut u32,
+        kind: LayerKind,
+        nb_inputs: usize,
+        nb_neurons: usize,
+        activation: Activation,
+    ) -> Layer {
+        let mut weights = |len: usize| {
+            let w: Vec<i8> = (0..len)
+                .map(|_| {
+                    *seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    ((*seed >> 16) % 16) as i8 - 8
+                })
+                .collect();
+            Weights::I8(w.into())
+        };
+        match kind {
+            LayerKind::Dense => Layer::Dense(DenseLayer {
+                nb_inputs,
+                nb_neurons,
+                input_weights: weights(nb_inputs * nb_neurons),
+                bias: weights(nb_neurons),
+                scale: WEIGHTS_SCALE,
+                activation,
+            }),
+            LayerKind::Gru => Layer::Gru(GruLayer {
+                nb_inputs,
+                nb_neurons,
+                input_weights: weights(3 * nb_inputs * nb_neurons),
+                recurrent_weights: weights(3 * nb_neurons * nb_neurons),
+                bias: weights(3 * nb_neurons),
+                scale: WEIGHTS_SCALE,
+                activation,
+            }),
+        }
+    }
+
+    /// Builds a model with the given layer sizes and some arbitrary small weights.
+    fn model_with_sizes(dense: usize, vad: usize, noise: usize, denoise: usize) -> RnnModel {
+        let mut seed = 1;
+        let mut layer = |kind, nb_inputs, nb_neurons, activation| {
+            random_layer(&mut seed, kind, nb_inputs, nb_neurons, activation)
+        };
+        RnnModel::rnnoise(vec![
+            layer(LayerKind::Dense, INPUT_SIZE, dense, Activation::Tanh),
+            layer(LayerKind::Gru, dense, vad, Activation::Relu),
+            layer(
+                LayerKind::Gru,
+                INPUT_SIZE + dense + vad,
+                noise,
+                Activation::Relu,
+            ),
+            layer(
+                LayerKind::Gru,
+                INPUT_SIZE + vad + noise,
+                denoise,
+                Activation::Tanh,
+            ),
+            layer(
+                LayerKind::Dense,
+                denoise,
+                crate::NB_BANDS,
+                Activation::Sigmoid,
+            ),
+            layer(LayerKind::Dense, vad, 1, Activation::Sigmoid),
+        ])
+    }
+
+    #[test]
+    fn large_layers() {
+        let model = model_with_sizes(200, 130, 300, 256);
+        let read = RnnModel::from_bytes(&model.to_bytes().unwrap()).unwrap();
+        assert_eq!(read.to_bytes().unwrap(), model.to_bytes().unwrap());
+        assert_eq!(read.layers()[2].nb_neurons, 300);
+
+        // The legacy format can't store layers this big.
+        assert!(model.to_legacy_bytes().is_err());
+
+        let mut state = crate::DenoiseState::from_model(read);
+        let input: Vec<f32> = (0..crate::DenoiseState::FRAME_SIZE)
+            .map(|i| (i as f32 * 0.1).sin() * 10_000.0)
+            .collect();
+        let mut output = vec![0.0; crate::DenoiseState::FRAME_SIZE];
+        for _ in 0..10 {
+            let vad = state.process_frame(&mut output, &input);
+            assert!((0.0..=1.0).contains(&vad));
+            assert!(output.iter().all(|x| x.is_finite()));
+        }
+    }
+
+    /// Builds a model with two denoising GRUs and no voice activity detection.
+    fn graph_model() -> RnnModel {
+        use Activation::*;
+        use LayerKind::*;
+        let layers = vec![
+            ("input_dense", Dense, vec![Source::Features], 24, Tanh),
+            (
+                "noise_gru",
+                Gru,
+                vec![Source::Layer(0), Source::Features],
+                32,
+                Relu,
+            ),
+            (
+                "denoise_gru_1",
+                Gru,
+                vec![Source::Layer(1), Source::Features],
+                32,
+                Relu,
+            ),
+            ("denoise_gru_2", Gru, vec![Source::Layer(2)], 32, Tanh),
+            (
+                "gains",
+                Dense,
+                vec![Source::Layer(3)],
+                crate::NB_BANDS,
+                Sigmoid,
+            ),
+        ];
+
+        let mut seed = 1;
+        let mut model = RnnModel {
+            nodes: Vec::new(),
+            gains: 4,
+            vad: None,
+            metadata: ModelMetadata::default(),
+        };
+        for (name, kind, inputs, nb_neurons, activation) in layers {
+            let nb_inputs = inputs.iter().map(|&s| model.source_size(s)).sum();
+            model.nodes.push(Node {
+                name: name.to_owned(),
+                layer: random_layer(&mut seed, kind, nb_inputs, nb_neurons, activation),
+                inputs,
+            });
+        }
+        model
+    }
+
+    #[test]
+    fn graph() {
+        let model = graph_model();
+        let bytes = model.to_bytes().unwrap();
+        let read = RnnModel::from_bytes(&bytes).unwrap();
+        assert_eq!(read.to_bytes().unwrap(), bytes);
+        assert_eq!(read.gains_layer(), "gains");
+        assert_eq!(read.vad_layer(), None);
+        let layers = read.layers();
+        assert_eq!(layers.len(), 5);
+        assert_eq!(layers[2].name, "denoise_gru_1");
+        assert_eq!(layers[2].inputs, ["noise_gru", "features"]);
+        assert_eq!(layers[2].nb_inputs, 32 + INPUT_SIZE);
+        assert!(read.to_legacy_bytes().is_err());
+
+        // Without a VAD layer, every frame that isn't silent is reported as voice.
+        let mut state = crate::DenoiseState::from_model(read);
+        let input: Vec<f32> = (0..crate::DenoiseState::FRAME_SIZE)
+            .map(|i| (i as f32 * 0.1).sin() * 10_000.0)
+            .collect();
+        let mut output = vec![0.0; crate::DenoiseState::FRAME_SIZE];
+        for _ in 0..10 {
+            assert_eq!(state.process_frame(&mut output, &input), 1.0);
+            assert!(output.iter().all(|x| x.is_finite()));
+        }
+    }
+
+    #[test]
+    fn graph_errors() {
+        // A layer that takes input from a later layer.
+        let mut model = graph_model();
+        model.nodes[1].inputs[0] = Source::Layer(2);
+        assert!(matches!(
+            RnnModel::from_bytes(&model.to_bytes().unwrap()),
+            Err(ModelError::UnknownLayer { name, .. }) if name == "denoise_gru_1"
+        ));
+
+        let mut model = graph_model();
+        model.nodes[2].name = "noise_gru".to_owned();
+        assert!(matches!(
+            RnnModel::from_bytes(&model.to_bytes().unwrap()),
+            Err(ModelError::DuplicateLayer { name, .. }) if name == "noise_gru"
+        ));
+
+        let mut model = graph_model();
+        model.nodes[2].inputs.pop();
+        assert!(matches!(
+            RnnModel::from_bytes(&model.to_bytes().unwrap()),
+            Err(ModelError::DimensionMismatch { field, expected: 32, actual, .. })
+                if field == "denoise_gru_1.nb_inputs" && actual == 32 + INPUT_SIZE
+        ));
+
+        let mut model = graph_model();
+        model.gains = 3;
+        assert!(matches!(
+            RnnModel::from_bytes(&model.to_bytes().unwrap()),
+            Err(ModelError::DimensionMismatch { field, expected, actual: 32, .. })
+                if field == "denoise_gru_2.nb_neurons" && expected == crate::NB_BANDS
+        ));
+
+        // A layer with no neurons, even one whose output isn't used, would break the denoiser.
+        let mut model = graph_model();
+        let mut seed = 1;
+        model.nodes.push(Node {
+            name: "empty".to_owned(),
+            layer: random_layer(&mut seed, LayerKind::Dense, 24, 0, Activation::Tanh),
+            inputs: vec![Source::Layer(0)],
+        });
+        assert!(matches!(
+            RnnModel::from_bytes(&model.to_bytes().unwrap()),
+            Err(ModelError::InvalidDimension { layer, value: 0, .. }) if layer == "empty"
+        ));
+    }
 }
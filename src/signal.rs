@@ -1,17 +1,19 @@
 //! An adaptation of our denoising to dasp Signals.
 
+use std::sync::Arc;
+
 use dasp::frame::Frame;
 use dasp::sample::Sample;
 use dasp::signal::Signal;
-use std::borrow::Cow;
 
-use crate::{DenoiseState, RnnModel, FRAME_SIZE};
+use crate::{RnnModel, SampleScale, StreamingDenoiser, FRAME_SIZE};
 
 /// Applies denoising to a `Signal` (from the `dasp` crate).
 ///
 /// Instantiate one of these with a `Signal` as input, and you'll get a `Signal` that yields
 /// denoised audio. Note that the denoised `Signal` will be in floating-point, even if the
-/// original signal wasn't.
+/// original signal wasn't. If the input `Signal` is finite, the denoised `Signal` will have
+/// the same length.
 ///
 /// # Example
 /// ```rust
@@ -28,23 +30,19 @@ use crate::{DenoiseState, RnnModel, FRAME_SIZE};
 #[derive(Clone)]
 pub struct DenoiseSignal<'model, S: Signal> {
     input: S,
-    states: Vec<DenoiseState<'model>>,
-    in_bufs: Vec<[f32; FRAME_SIZE]>,
-    out_bufs: Vec<[f32; FRAME_SIZE]>,
+    denoisers: Vec<StreamingDenoiser<'model>>,
+    in_bufs: Vec<Vec<f32>>,
+    out_bufs: Vec<Vec<f32>>,
     out_idx: usize,
+    primed: bool,
+    /// True if the input was exhausted and we flushed the denoisers.
+    flushed: bool,
 }
 
 impl<'model, S: Signal> DenoiseSignal<'model, S> {
     /// Creates a new `DenoiseSignal` using the built-in default noise model.
     pub fn new(input: S) -> DenoiseSignal<'static, S> {
-        DenoiseSignal {
-            input,
-            states: vec![DenoiseState::default(); S::Frame::CHANNELS],
-            in_bufs: vec![[0.0; FRAME_SIZE]; S::Frame::CHANNELS],
-            out_bufs: vec![[0.0; FRAME_SIZE]; S::Frame::CHANNELS],
-            out_idx: 0,
-        }
-        .discard_first_frame()
+        DenoiseSignal::from_denoiser(input, StreamingDenoiser::new())
     }
 
     /// Creates a new `DenoiseSignal` using a custom noise model.
@@ -53,14 +51,7 @@ impl<'model, S: Signal> DenoiseSignal<'model, S> {
     /// `DenoiseSignal` will borrow the model and reuse it for the different channels in the
     /// signal.
     pub fn with_model(input: S, model: &'model RnnModel) -> DenoiseSignal<'model, S> {
-        DenoiseSignal {
-            input,
-            states: vec![DenoiseState::from_model_owned(Cow::Borrowed(model)); S::Frame::CHANNELS],
-            in_bufs: vec![[0.0; FRAME_SIZE]; S::Frame::CHANNELS],
-            out_bufs: vec![[0.0; FRAME_SIZE]; S::Frame::CHANNELS],
-            out_idx: 0,
-        }
-        .discard_first_frame()
+        DenoiseSignal::from_denoiser(input, StreamingDenoiser::with_model(model))
     }
 
     /// Creates a new `DenoiseSignal` owning a custom noise model.
@@ -70,39 +61,107 @@ impl<'model, S: Signal> DenoiseSignal<'model, S> {
     /// signal. If the model is cheap to clone (for example, because it was created with
     /// [`RnnModel::from_static_bytes`](crate::RnnModel::from_static_bytes) then this is fine.
     pub fn from_model(input: S, model: RnnModel) -> DenoiseSignal<'static, S> {
+        DenoiseSignal::from_denoiser(input, StreamingDenoiser::from_model(model))
+    }
+
+    /// Creates a new `DenoiseSignal` sharing a custom noise model.
+    ///
+    /// Unlike `DenoiseSignal::with_model`, this doesn't borrow the model, and unlike
+    /// `DenoiseSignal::from_model`, the channels of the signal share one copy of the model.
+    pub fn from_shared_model(input: S, model: Arc<RnnModel>) -> DenoiseSignal<'static, S> {
+        DenoiseSignal::from_denoiser(input, StreamingDenoiser::from_shared_model(model))
+    }
+
+    fn from_denoiser(
+        input: S,
+        mut denoiser: StreamingDenoiser<'model>,
+    ) -> DenoiseSignal<'model, S> {
+        denoiser
+            .state_mut()
+            .set_sample_scale(SampleScale::Normalized);
         DenoiseSignal {
             input,
-            states: vec![DenoiseState::from_model_owned(Cow::Owned(model)); S::Frame::CHANNELS],
-            in_bufs: vec![[0.0; FRAME_SIZE]; S::Frame::CHANNELS],
-            out_bufs: vec![[0.0; FRAME_SIZE]; S::Frame::CHANNELS],
+            denoisers: vec![denoiser; S::Frame::CHANNELS],
+            in_bufs: vec![Vec::with_capacity(FRAME_SIZE); S::Frame::CHANNELS],
+            out_bufs: vec![Vec::with_capacity(FRAME_SIZE); S::Frame::CHANNELS],
             out_idx: 0,
+            primed: false,
+            flushed: false,
         }
-        .discard_first_frame()
     }
 
-    fn discard_first_frame(mut self) -> Self {
-        self.refill_out_bufs();
-        self.refill_out_bufs();
+    /// Sets the sample rate of the signal.
+    ///
+    /// By default, the signal is assumed to be sampled at 48kHz. See
+    /// [`DenoiseState::set_sample_rate`](crate::DenoiseState::set_sample_rate) for the other
+    /// supported sample rates.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` is not a supported sample rate.
+    pub fn with_sample_rate(mut self, rate: u32) -> Self {
+        for denoiser in &mut self.denoisers {
+            denoiser.state_mut().set_sample_rate(rate);
+        }
         self
     }
 
-    /// Returns true if the input was not exhausted.
-    fn refill_out_bufs(&mut self) -> bool {
-        if self.input.is_exhausted() {
-            return false;
+    /// Limits the amount by which any frequency band will be attenuated.
+    ///
+    /// See [`DenoiseState::set_max_attenuation`](crate::DenoiseState::set_max_attenuation) for details.
+    pub fn with_max_attenuation(mut self, db: f32) -> Self {
+        for denoiser in &mut self.denoisers {
+            denoiser.state_mut().set_max_attenuation(db);
         }
+        self
+    }
 
-        for i in 0..FRAME_SIZE {
-            for (ch, samp) in self.input.next().to_float_frame().channels().enumerate() {
-                // Our denoiser expects f32s, but they should be in the range of an i16.
-                self.in_bufs[ch][i] = samp.to_sample::<f32>() * 32768.0;
-            }
+    /// Sets the balance between the denoised signal and the original signal.
+    ///
+    /// See [`DenoiseState::set_mix`](crate::DenoiseState::set_mix) for details.
+    pub fn with_mix(mut self, mix: f32) -> Self {
+        for denoiser in &mut self.denoisers {
+            denoiser.state_mut().set_mix(mix);
+        }
+        self
+    }
+
+    // Replaces the (fully consumed) output buffers with the next batch of output, reading as much
+    // input as necessary. If there is no more output, the output buffers will be left empty.
+    //
+    // We don't start processing the input until the first time we're asked for output, so that
+    // the denoising parameters can be changed after construction.
+    fn refill_out_bufs(&mut self) {
+        self.primed = true;
+        self.out_idx = 0;
+        for buf in &mut self.out_bufs {
+            buf.clear();
         }
 
-        for ch in 0..S::Frame::CHANNELS {
-            self.states[ch].process_frame(&mut self.out_bufs[ch][..], &self.in_bufs[ch][..]);
+        while self.out_bufs[0].is_empty() && !self.flushed {
+            if self.input.is_exhausted() {
+                for (denoiser, out) in self.denoisers.iter_mut().zip(&mut self.out_bufs) {
+                    denoiser.flush(out);
+                }
+                self.flushed = true;
+            } else {
+                for buf in &mut self.in_bufs {
+                    buf.clear();
+                }
+                for _ in 0..FRAME_SIZE {
+                    if self.input.is_exhausted() {
+                        break;
+                    }
+                    for (ch, samp) in self.input.next().to_float_frame().channels().enumerate() {
+                        self.in_bufs[ch].push(samp.to_sample::<f32>());
+                    }
+                }
+
+                for (ch, denoiser) in self.denoisers.iter_mut().enumerate() {
+                    denoiser.process(&self.in_bufs[ch], &mut self.out_bufs[ch]);
+                }
+            }
         }
-        !self.input.is_exhausted()
     }
 }
 
@@ -110,27 +169,31 @@ impl<'model, S: Signal> Signal for DenoiseSignal<'model, S> {
     type Frame = <<S as Signal>::Frame as Frame>::Float;
 
     fn is_exhausted(&self) -> bool {
-        self.out_idx >= FRAME_SIZE
+        if self.primed {
+            self.out_idx >= self.out_bufs[0].len()
+        } else {
+            self.input.is_exhausted()
+        }
     }
 
     fn next(&mut self) -> Self::Frame {
-        if self.out_idx >= FRAME_SIZE {
+        if !self.primed {
+            self.refill_out_bufs();
+        }
+        if self.out_idx >= self.out_bufs[0].len() {
             return Self::Frame::EQUILIBRIUM;
         }
 
         let idx = self.out_idx;
         self.out_idx += 1;
         let ret = Frame::from_fn(|ch| {
-            // Undo the denoiser's peculiar choice of scaling.
-            let samp = (self.out_bufs[ch][idx] / 32768.0).clamp(-1.0, 1.0);
+            let samp = self.out_bufs[ch][idx].clamp(-1.0, 1.0);
             samp.to_sample()
         });
 
         // Process the next frame early, because it makes `is_exhausted` more accurate.
-        if self.out_idx >= FRAME_SIZE {
-            if self.refill_out_bufs() {
-                self.out_idx = 0;
-            }
+        if self.out_idx >= self.out_bufs[0].len() {
+            self.refill_out_bufs();
         }
 
         ret
@@ -0,0 +1,639 @@
+//! SIMD kernels for the neural network.
+//!
+//! Running the network is mostly a matter of multiplying weight matrices by vectors (see
+//! [`mul_add`]) and applying activation functions (see [`tansig`] and [`sigmoid`]). These
+//! functions have versions for SSE2 and AVX2 on x86, and for NEON on aarch64. With the `std`
+//! feature, we choose between them at run time, depending on what the CPU supports; without it,
+//! we go by the target features that were enabled at compile time. Either way, we fall back to
+//! plain Rust code if none of them are available.
+//!
+//! The SIMD versions do exactly the same floating-point operations as the plain ones, in the same
+//! order (in particular, they don't fuse multiplications and additions), so they give exactly the
+//! same results.
+
+#[cfg(target_arch = "aarch64")]
+use core::arch::aarch64::*;
+#[cfg(target_arch = "x86")]
+use core::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::*;
+
+use crate::util::{sigmoid_approx, tansig_approx, TANSIG_TABLE};
+
+/// The instruction sets that we have kernels for.
+///
+/// Having anything other than `Isa::Scalar` is a promise that the CPU supports it, so these
+/// should only be obtained from [`Isa::detect`] or [`Isa::supported`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Isa {
+    Scalar,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Sse2,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Avx2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+#[cfg(feature = "std")]
+macro_rules! has_feature {
+    ("neon") => {
+        std::arch::is_aarch64_feature_detected!("neon")
+    };
+    ($feature:tt) => {
+        std::is_x86_feature_detected!($feature)
+    };
+}
+
+#[cfg(not(feature = "std"))]
+macro_rules! has_feature {
+    ($feature:tt) => {
+        cfg!(target_feature = $feature)
+    };
+}
+
+impl Isa {
+    /// All the instruction sets, from best to worst.
+    const ALL: &'static [Isa] = &[
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Isa::Avx2,
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Isa::Sse2,
+        #[cfg(target_arch = "aarch64")]
+        Isa::Neon,
+        Isa::Scalar,
+    ];
+
+    /// Returns the best instruction set that this CPU supports.
+    pub(crate) fn detect() -> Isa {
+        Isa::supported().next().unwrap_or(Isa::Scalar)
+    }
+
+    /// Returns all the instruction sets that this CPU supports, from best to worst.
+    pub(crate) fn supported() -> impl Iterator<Item = Isa> {
+        Isa::ALL.iter().copied().filter(|isa| isa.is_supported())
+    }
+
+    fn is_supported(self) -> bool {
+        match self {
+            Isa::Scalar => true,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Isa::Sse2 => has_feature!("sse2"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Isa::Avx2 => has_feature!("avx2"),
+            #[cfg(target_arch = "aarch64")]
+            Isa::Neon => has_feature!("neon"),
+        }
+    }
+}
+
+/// The types that weights can be stored as.
+///
+/// Besides converting single weights, this loads eight consecutive weights at a time into SIMD
+/// registers, converting them to `f32`s. The loads are unsafe because they don't check that
+/// there are eight weights to read.
+pub(crate) trait Weight: Copy {
+    fn to_f32(self) -> f32;
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    unsafe fn load8_sse2(ptr: *const Self) -> (__m128, __m128);
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    unsafe fn load8_avx2(ptr: *const Self) -> __m256;
+
+    #[cfg(target_arch = "aarch64")]
+    unsafe fn load8_neon(ptr: *const Self) -> (float32x4_t, float32x4_t);
+}
+
+impl Weight for i8 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn load8_sse2(ptr: *const i8) -> (__m128, __m128) {
+        // SSE2 can't sign-extend directly, so we put each byte in the top of a 32-bit lane and
+        // shift it back down.
+        let x = _mm_loadl_epi64(ptr as *const __m128i);
+        let x = _mm_unpacklo_epi8(x, x);
+        let lo = _mm_srai_epi32(_mm_unpacklo_epi16(x, x), 24);
+        let hi = _mm_srai_epi32(_mm_unpackhi_epi16(x, x), 24);
+        (_mm_cvtepi32_ps(lo), _mm_cvtepi32_ps(hi))
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn load8_avx2(ptr: *const i8) -> __m256 {
+        let x = _mm_loadl_epi64(ptr as *const __m128i);
+        _mm256_cvtepi32_ps(_mm256_cvtepi8_epi32(x))
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn load8_neon(ptr: *const i8) -> (float32x4_t, float32x4_t) {
+        let x = vmovl_s8(vld1_s8(ptr));
+        let lo = vmovl_s16(vget_low_s16(x));
+        let hi = vmovl_high_s16(x);
+        (vcvtq_f32_s32(lo), vcvtq_f32_s32(hi))
+    }
+}
+
+impl Weight for i16 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn load8_sse2(ptr: *const i16) -> (__m128, __m128) {
+        let x = _mm_loadu_si128(ptr as *const __m128i);
+        let lo = _mm_srai_epi32(_mm_unpacklo_epi16(x, x), 16);
+        let hi = _mm_srai_epi32(_mm_unpackhi_epi16(x, x), 16);
+        (_mm_cvtepi32_ps(lo), _mm_cvtepi32_ps(hi))
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn load8_avx2(ptr: *const i16) -> __m256 {
+        let x = _mm_loadu_si128(ptr as *const __m128i);
+        _mm256_cvtepi32_ps(_mm256_cvtepi16_epi32(x))
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn load8_neon(ptr: *const i16) -> (float32x4_t, float32x4_t) {
+        let x = vld1q_s16(ptr);
+        let lo = vmovl_s16(vget_low_s16(x));
+        let hi = vmovl_high_s16(x);
+        (vcvtq_f32_s32(lo), vcvtq_f32_s32(hi))
+    }
+}
+
+impl Weight for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn load8_sse2(ptr: *const f32) -> (__m128, __m128) {
+        (_mm_loadu_ps(ptr), _mm_loadu_ps(ptr.add(4)))
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn load8_avx2(ptr: *const f32) -> __m256 {
+        _mm256_loadu_ps(ptr)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn load8_neon(ptr: *const f32) -> (float32x4_t, float32x4_t) {
+        (vld1q_f32(ptr), vld1q_f32(ptr.add(4)))
+    }
+}
+
+/// Multiplies a submatrix of `data` by `input`, adding the result to `output`.
+///
+/// `data` is stored column by column, with each column taking up `stride` elements; the
+/// submatrix consists of the rows from `offset` to `offset + output.len()`. That is, this adds
+/// `data[j * stride + offset + i] * input[j]` to `output[i]`, for every `i` and `j`.
+pub(crate) fn mul_add<T: Weight>(
+    isa: Isa,
+    output: &mut [f32],
+    data: &[T],
+    stride: usize,
+    offset: usize,
+    input: &[f32],
+) {
+    assert!(offset + output.len() <= stride);
+    let cols = input.len().min(data.len() / stride);
+    let input = &input[..cols];
+    let data = &data[..(cols * stride)];
+
+    // The SIMD kernels do blocks of eight outputs, and leave the rest for the scalar one.
+    let simd_len = if isa == Isa::Scalar {
+        0
+    } else {
+        output.len() - output.len() % 8
+    };
+    let (simd_output, rest) = output.split_at_mut(simd_len);
+    // Safety: the kernels only read the rows from `offset` to `offset + simd_len` of the first
+    // `cols` columns, which we checked are in bounds. The CPU supports `isa`, because that's
+    // the only way to get one.
+    unsafe {
+        match isa {
+            Isa::Scalar => {}
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Isa::Sse2 => mul_add_sse2(simd_output, data, stride, offset, input),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Isa::Avx2 => mul_add_avx2(simd_output, data, stride, offset, input),
+            #[cfg(target_arch = "aarch64")]
+            Isa::Neon => mul_add_neon(simd_output, data, stride, offset, input),
+        }
+    }
+    mul_add_scalar(rest, data, stride, offset + simd_len, input);
+}
+
+fn mul_add_scalar<T: Weight>(
+    output: &mut [f32],
+    data: &[T],
+    stride: usize,
+    offset: usize,
+    input: &[f32],
+) {
+    for (col, input) in data.chunks_exact(stride).zip(input) {
+        for (&x, out) in col[offset..].iter().zip(&mut *output) {
+            *out += x.to_f32() * input;
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn mul_add_sse2<T: Weight>(
+    output: &mut [f32],
+    data: &[T],
+    stride: usize,
+    offset: usize,
+    input: &[f32],
+) {
+    for (block, out) in output.chunks_exact_mut(8).enumerate() {
+        let start = offset + 8 * block;
+        let mut lo = _mm_loadu_ps(out.as_ptr());
+        let mut hi = _mm_loadu_ps(out.as_ptr().add(4));
+        for (j, &x) in input.iter().enumerate() {
+            let (w_lo, w_hi) = T::load8_sse2(data.as_ptr().add(j * stride + start));
+            let x = _mm_set1_ps(x);
+            lo = _mm_add_ps(lo, _mm_mul_ps(w_lo, x));
+            hi = _mm_add_ps(hi, _mm_mul_ps(w_hi, x));
+        }
+        _mm_storeu_ps(out.as_mut_ptr(), lo);
+        _mm_storeu_ps(out.as_mut_ptr().add(4), hi);
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn mul_add_avx2<T: Weight>(
+    output: &mut [f32],
+    data: &[T],
+    stride: usize,
+    offset: usize,
+    input: &[f32],
+) {
+    for (block, out) in output.chunks_exact_mut(8).enumerate() {
+        let start = offset + 8 * block;
+        let mut acc = _mm256_loadu_ps(out.as_ptr());
+        for (j, &x) in input.iter().enumerate() {
+            let w = T::load8_avx2(data.as_ptr().add(j * stride + start));
+            acc = _mm256_add_ps(acc, _mm256_mul_ps(w, _mm256_set1_ps(x)));
+        }
+        _mm256_storeu_ps(out.as_mut_ptr(), acc);
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn mul_add_neon<T: Weight>(
+    output: &mut [f32],
+    data: &[T],
+    stride: usize,
+    offset: usize,
+    input: &[f32],
+) {
+    for (block, out) in output.chunks_exact_mut(8).enumerate() {
+        let start = offset + 8 * block;
+        let mut lo = vld1q_f32(out.as_ptr());
+        let mut hi = vld1q_f32(out.as_ptr().add(4));
+        for (j, &x) in input.iter().enumerate() {
+            let (w_lo, w_hi) = T::load8_neon(data.as_ptr().add(j * stride + start));
+            let x = vdupq_n_f32(x);
+            lo = vaddq_f32(lo, vmulq_f32(w_lo, x));
+            hi = vaddq_f32(hi, vmulq_f32(w_hi, x));
+        }
+        vst1q_f32(out.as_mut_ptr(), lo);
+        vst1q_f32(out.as_mut_ptr().add(4), hi);
+    }
+}
+
+/// Replaces each `x` in `xs` by `tansig_approx(scale * x)`.
+pub(crate) fn tansig(isa: Isa, xs: &mut [f32], scale: f32) {
+    activation(isa, xs, scale, false);
+}
+
+/// Replaces each `x` in `xs` by `sigmoid_approx(scale * x)`.
+pub(crate) fn sigmoid(isa: Isa, xs: &mut [f32], scale: f32) {
+    activation(isa, xs, scale, true);
+}
+
+fn activation(isa: Isa, xs: &mut [f32], scale: f32, sigmoid: bool) {
+    // The number of values that the SIMD kernel does at a time.
+    let lanes = match isa {
+        Isa::Scalar => 0,
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Isa::Sse2 => 4,
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Isa::Avx2 => 8,
+        #[cfg(target_arch = "aarch64")]
+        Isa::Neon => 4,
+    };
+    let simd_len = if lanes == 0 {
+        0
+    } else {
+        xs.len() - xs.len() % lanes
+    };
+    let (simd_xs, rest) = xs.split_at_mut(simd_len);
+    // Safety: the CPU supports `isa`, because that's the only way to get one.
+    unsafe {
+        match isa {
+            Isa::Scalar => {}
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Isa::Sse2 => activation_sse2(simd_xs, scale, sigmoid),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Isa::Avx2 => activation_avx2(simd_xs, scale, sigmoid),
+            #[cfg(target_arch = "aarch64")]
+            Isa::Neon => activation_neon(simd_xs, scale, sigmoid),
+        }
+    }
+    for x in rest {
+        *x = if sigmoid {
+            sigmoid_approx(scale * *x)
+        } else {
+            tansig_approx(scale * *x)
+        };
+    }
+}
+
+// The vectorized versions of `tansig_approx` follow the scalar one step by step. The differences
+// are that they compute all the branches and then choose between them, and that they look up
+// the table with out-of-range inputs replaced by zero so that the index is always valid. Since
+// `0.5 + 25.0 * x` is positive, rounding it towards zero is the same as taking its floor.
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn tansig_sse2(x: __m128) -> __m128 {
+    let one = _mm_set1_ps(1.0);
+    let minus_one = _mm_set1_ps(-1.0);
+    // As in the scalar version, the tests are reversed to catch NaNs.
+    let big = _mm_cmpnlt_ps(x, _mm_set1_ps(8.0));
+    let small = _mm_cmpngt_ps(x, _mm_set1_ps(-8.0));
+    let sign = select_sse2(_mm_cmplt_ps(x, _mm_setzero_ps()), minus_one, one);
+    let x = _mm_andnot_ps(_mm_set1_ps(-0.0), x);
+    let x = _mm_andnot_ps(_mm_or_ps(big, small), x);
+
+    let i = _mm_cvttps_epi32(_mm_add_ps(
+        _mm_set1_ps(0.5),
+        _mm_mul_ps(_mm_set1_ps(25.0), x),
+    ));
+    let x = _mm_sub_ps(x, _mm_mul_ps(_mm_set1_ps(0.04), _mm_cvtepi32_ps(i)));
+    let mut idx = [0i32; 4];
+    _mm_storeu_si128(idx.as_mut_ptr() as *mut __m128i, i);
+    let y = _mm_setr_ps(
+        TANSIG_TABLE[idx[0] as usize],
+        TANSIG_TABLE[idx[1] as usize],
+        TANSIG_TABLE[idx[2] as usize],
+        TANSIG_TABLE[idx[3] as usize],
+    );
+    let dy = _mm_sub_ps(one, _mm_mul_ps(y, y));
+    let y = _mm_add_ps(
+        y,
+        _mm_mul_ps(_mm_mul_ps(x, dy), _mm_sub_ps(one, _mm_mul_ps(y, x))),
+    );
+    let y = _mm_mul_ps(sign, y);
+    select_sse2(big, one, select_sse2(small, minus_one, y))
+}
+
+/// Returns the lanes of `a` where `mask` is set, and the lanes of `b` elsewhere.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn select_sse2(mask: __m128, a: __m128, b: __m128) -> __m128 {
+    _mm_or_ps(_mm_and_ps(mask, a), _mm_andnot_ps(mask, b))
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn activation_sse2(xs: &mut [f32], scale: f32, sigmoid: bool) {
+    let half = _mm_set1_ps(0.5);
+    let scale = _mm_set1_ps(scale);
+    for x in xs.chunks_exact_mut(4) {
+        let v = _mm_mul_ps(scale, _mm_loadu_ps(x.as_ptr()));
+        let v = if sigmoid {
+            _mm_add_ps(half, _mm_mul_ps(half, tansig_sse2(_mm_mul_ps(half, v))))
+        } else {
+            tansig_sse2(v)
+        };
+        _mm_storeu_ps(x.as_mut_ptr(), v);
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn tansig_avx2(x: __m256) -> __m256 {
+    let one = _mm256_set1_ps(1.0);
+    let minus_one = _mm256_set1_ps(-1.0);
+    // As in the scalar version, the tests are reversed to catch NaNs.
+    let big = _mm256_cmp_ps(x, _mm256_set1_ps(8.0), _CMP_NLT_UQ);
+    let small = _mm256_cmp_ps(x, _mm256_set1_ps(-8.0), _CMP_NGT_UQ);
+    let negative = _mm256_cmp_ps(x, _mm256_setzero_ps(), _CMP_LT_OQ);
+    let sign = _mm256_blendv_ps(one, minus_one, negative);
+    let x = _mm256_andnot_ps(_mm256_set1_ps(-0.0), x);
+    let x = _mm256_andnot_ps(_mm256_or_ps(big, small), x);
+
+    let i = _mm256_cvttps_epi32(_mm256_add_ps(
+        _mm256_set1_ps(0.5),
+        _mm256_mul_ps(_mm256_set1_ps(25.0), x),
+    ));
+    let x = _mm256_sub_ps(
+        x,
+        _mm256_mul_ps(_mm256_set1_ps(0.04), _mm256_cvtepi32_ps(i)),
+    );
+    let y = _mm256_i32gather_ps(TANSIG_TABLE.as_ptr(), i, 4);
+    let dy = _mm256_sub_ps(one, _mm256_mul_ps(y, y));
+    let y = _mm256_add_ps(
+        y,
+        _mm256_mul_ps(
+            _mm256_mul_ps(x, dy),
+            _mm256_sub_ps(one, _mm256_mul_ps(y, x)),
+        ),
+    );
+    let y = _mm256_mul_ps(sign, y);
+    _mm256_blendv_ps(_mm256_blendv_ps(y, minus_one, small), one, big)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn activation_avx2(xs: &mut [f32], scale: f32, sigmoid: bool) {
+    let half = _mm256_set1_ps(0.5);
+    let scale = _mm256_set1_ps(scale);
+    for x in xs.chunks_exact_mut(8) {
+        let v = _mm256_mul_ps(scale, _mm256_loadu_ps(x.as_ptr()));
+        let v = if sigmoid {
+            _mm256_add_ps(
+                half,
+                _mm256_mul_ps(half, tansig_avx2(_mm256_mul_ps(half, v))),
+            )
+        } else {
+            tansig_avx2(v)
+        };
+        _mm256_storeu_ps(x.as_mut_ptr(), v);
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn tansig_neon(x: float32x4_t) -> float32x4_t {
+    let one = vdupq_n_f32(1.0);
+    let minus_one = vdupq_n_f32(-1.0);
+    // As in the scalar version, the tests are reversed to catch NaNs.
+    let big = vmvnq_u32(vcltq_f32(x, vdupq_n_f32(8.0)));
+    let small = vmvnq_u32(vcgtq_f32(x, vdupq_n_f32(-8.0)));
+    let sign = vbslq_f32(vcltq_f32(x, vdupq_n_f32(0.0)), minus_one, one);
+    let x = vbslq_f32(vorrq_u32(big, small), vdupq_n_f32(0.0), vabsq_f32(x));
+
+    let i = vcvtq_u32_f32(vaddq_f32(vdupq_n_f32(0.5), vmulq_f32(vdupq_n_f32(25.0), x)));
+    let x = vsubq_f32(x, vmulq_f32(vdupq_n_f32(0.04), vcvtq_f32_u32(i)));
+    let mut idx = [0u32; 4];
+    vst1q_u32(idx.as_mut_ptr(), i);
+    let y = [
+        TANSIG_TABLE[idx[0] as usize],
+        TANSIG_TABLE[idx[1] as usize],
+        TANSIG_TABLE[idx[2] as usize],
+        TANSIG_TABLE[idx[3] as usize],
+    ];
+    let y = vld1q_f32(y.as_ptr());
+    let dy = vsubq_f32(one, vmulq_f32(y, y));
+    let y = vaddq_f32(
+        y,
+        vmulq_f32(vmulq_f32(x, dy), vsubq_f32(one, vmulq_f32(y, x))),
+    );
+    let y = vmulq_f32(sign, y);
+    vbslq_f32(big, one, vbslq_f32(small, minus_one, y))
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn activation_neon(xs: &mut [f32], scale: f32, sigmoid: bool) {
+    let half = vdupq_n_f32(0.5);
+    let scale = vdupq_n_f32(scale);
+    for x in xs.chunks_exact_mut(4) {
+        let v = vmulq_f32(scale, vld1q_f32(x.as_ptr()));
+        let v = if sigmoid {
+            vaddq_f32(half, vmulq_f32(half, tansig_neon(vmulq_f32(half, v))))
+        } else {
+            tansig_neon(v)
+        };
+        vst1q_f32(x.as_mut_ptr(), v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Some numbers that look random enough, between -1 and 1.
+    fn noise(len: usize, seed: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (((i + seed) * 7919 + seed * 104_729) % 2001) as f32 / 1000.0 - 1.0)
+            .collect()
+    }
+
+    /// Checks that the results from every supported instruction set match the scalar ones bit
+    /// for bit.
+    fn assert_same(isa: Isa, expected: &[f32], actual: &[f32]) {
+        assert_eq!(expected.len(), actual.len());
+        for (i, (x, y)) in expected.iter().zip(actual).enumerate() {
+            assert_eq!(
+                x.to_bits(),
+                y.to_bits(),
+                "{:?} differs at index {}: expected {}, got {}",
+                isa,
+                i,
+                x,
+                y
+            );
+        }
+    }
+
+    fn check_mul_add<T: Weight>(data: &[T], stride: usize, offset: usize, rows: usize) {
+        let input = noise(data.len() / stride, 1);
+        let initial = noise(rows, 2);
+        let mut expected = initial.clone();
+        mul_add(Isa::Scalar, &mut expected, data, stride, offset, &input);
+        for isa in Isa::supported() {
+            let mut actual = initial.clone();
+            mul_add(isa, &mut actual, data, stride, offset, &input);
+            assert_same(isa, &expected, &actual);
+        }
+    }
+
+    #[test]
+    fn mul_add_matches_scalar() {
+        // A GRU layer with 3 * 24 outputs, and some sizes that don't fit evenly in the registers.
+        for &(cols, stride, offset, rows) in &[
+            (42, 72, 24, 24),
+            (24, 72, 48, 24),
+            (13, 96, 0, 96),
+            (7, 21, 3, 17),
+            (5, 9, 1, 8),
+            (3, 3, 0, 3),
+        ] {
+            let weights = noise(cols * stride, 3);
+            let i8_data: Vec<i8> = weights.iter().map(|&w| (w * 127.0) as i8).collect();
+            let i16_data: Vec<i16> = weights.iter().map(|&w| (w * 32767.0) as i16).collect();
+            check_mul_add(&i8_data, stride, offset, rows);
+            check_mul_add(&i16_data, stride, offset, rows);
+            check_mul_add(&weights, stride, offset, rows);
+        }
+
+        // The extreme values of the integer types survive the conversion.
+        check_mul_add(&[i8::MIN, i8::MAX, -1, 0, 1, 2, 3, 4, 5, 6], 10, 0, 10);
+        check_mul_add(&[i16::MIN, i16::MAX, -1, 0, 1, 2, 3, 4, 5, 6], 10, 0, 10);
+    }
+
+    #[test]
+    fn activations_match_scalar() {
+        let mut xs: Vec<f32> = (-4200..=4200).map(|i| i as f32 / 400.0).collect();
+        xs.extend_from_slice(&[
+            -0.0,
+            8.0,
+            -8.0,
+            7.999_999,
+            -7.999_999,
+            f32::NAN,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::MAX,
+            f32::MIN_POSITIVE,
+        ]);
+
+        for &scale in &[1.0, 0.5, 1.0 / 256.0] {
+            let tansig_expected: Vec<f32> = xs.iter().map(|&x| tansig_approx(scale * x)).collect();
+            let sigmoid_expected: Vec<f32> =
+                xs.iter().map(|&x| sigmoid_approx(scale * x)).collect();
+            for isa in Isa::supported() {
+                let mut actual = xs.clone();
+                tansig(isa, &mut actual, scale);
+                assert_same(isa, &tansig_expected, &actual);
+
+                let mut actual = xs.clone();
+                sigmoid(isa, &mut actual, scale);
+                assert_same(isa, &sigmoid_expected, &actual);
+            }
+        }
+    }
+}
@@ -0,0 +1,315 @@
+//! A denoiser that accepts input of any length.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+
+use crate::error::check_finite;
+use crate::{DenoiseState, Error, RnnModel, FRAME_SIZE};
+
+/// A wrapper around [`DenoiseState`] that accepts input in chunks of any size.
+///
+/// `DenoiseState` insists on processing exactly `DenoiseState::FRAME_SIZE` samples at a time,
+/// and its output lags its input by one frame. `StreamingDenoiser` takes care of the buffering
+/// and the lag: you can feed it any number of samples at a time, and its output is aligned
+/// with its input (in the sense that the `n`th output sample is the denoised version of the `n`th
+/// input sample). Once the input is finished, call [`StreamingDenoiser::flush`] to get the
+/// rest of the output.
+///
+/// The input and output samples are `f32`s in the range of an `i16`, just like for
+/// [`DenoiseState::process_frame`]; to use the range `[-1.0, 1.0]` instead, see
+/// [`DenoiseState::set_sample_scale`].
+///
+/// # Example
+///
+/// ```rust
+/// # use nnnoiseless::StreamingDenoiser;
+/// let sine: Vec<_> = (0..48_000)
+///     .map(|x| (x as f32 * 440.0 * 2.0 * std::f32::consts::PI / 48_000.0).sin() * i16::MAX as f32)
+///     .collect();
+/// let mut output = Vec::new();
+/// let mut denoise = StreamingDenoiser::new();
+/// for chunk in sine.chunks(1000) {
+///     denoise.process(chunk, &mut output);
+/// }
+/// denoise.flush(&mut output);
+/// assert_eq!(output.len(), sine.len());
+/// ```
+#[derive(Clone)]
+pub struct StreamingDenoiser<'model> {
+    state: Box<DenoiseState<'model>>,
+    in_buf: [f32; FRAME_SIZE],
+    /// The number of samples in `in_buf` that are waiting to be processed.
+    in_len: usize,
+    out_buf: [f32; FRAME_SIZE],
+    /// The number of input samples in the current stream (including the ones in `in_buf`).
+    in_count: usize,
+    /// The number of output samples produced by the `DenoiseState` in the current stream
+    /// (including the ones we discarded).
+    out_count: usize,
+}
+
+impl StreamingDenoiser<'static> {
+    /// Creates a new `StreamingDenoiser`, using the built-in model.
+    pub fn new() -> StreamingDenoiser<'static> {
+        StreamingDenoiser::from_state(DenoiseState::new())
+    }
+
+    /// Creates a new `StreamingDenoiser` owning a custom model.
+    ///
+    /// See [`DenoiseState::from_model`].
+    pub fn from_model(model: RnnModel) -> StreamingDenoiser<'static> {
+        StreamingDenoiser::from_state(DenoiseState::from_model(model))
+    }
+
+    /// Creates a new `StreamingDenoiser` sharing a custom model.
+    ///
+    /// See [`DenoiseState::from_shared_model`].
+    pub fn from_shared_model(model: Arc<RnnModel>) -> StreamingDenoiser<'static> {
+        StreamingDenoiser::from_state(DenoiseState::from_shared_model(model))
+    }
+}
+
+impl<'model> StreamingDenoiser<'model> {
+    /// Creates a new `StreamingDenoiser` using a custom model.
+    ///
+    /// See [`DenoiseState::with_model`].
+    pub fn with_model(model: &'model RnnModel) -> StreamingDenoiser<'model> {
+        StreamingDenoiser::from_state(DenoiseState::with_model(model))
+    }
+
+    /// Creates a new `StreamingDenoiser` that uses the provided `DenoiseState` for processing.
+    ///
+    /// This is useful if you want to configure the `DenoiseState` first. It should be a fresh
+    /// `DenoiseState` that hasn't processed any audio yet.
+    pub fn from_state(state: Box<DenoiseState<'model>>) -> StreamingDenoiser<'model> {
+        StreamingDenoiser {
+            state,
+            in_buf: [0.0; FRAME_SIZE],
+            in_len: 0,
+            out_buf: [0.0; FRAME_SIZE],
+            in_count: 0,
+            out_count: 0,
+        }
+    }
+
+    /// The latency of this denoiser, in samples.
+    ///
+    /// The underlying `DenoiseState` processes one frame at a time, and its output lags its input
+    /// by `latency()` samples (see [`DenoiseState::latency`]). To be precise, once `n` samples
+    /// of input have been provided (and `flush` hasn't been called), `n` will be rounded down to a
+    /// multiple of the frame size, and then exactly `latency()` fewer samples than that will have
+    /// been output.
+    pub fn latency(&self) -> usize {
+        self.state.latency()
+    }
+
+    /// Returns the underlying `DenoiseState`.
+    pub fn state(&self) -> &DenoiseState<'model> {
+        &self.state
+    }
+
+    /// Returns the underlying `DenoiseState`, which can be used to change the denoising
+    /// parameters.
+    ///
+    /// The returned `DenoiseState` should only be used for configuration: if you use it to process
+    /// audio, the output of this `StreamingDenoiser` will be messed up.
+    pub fn state_mut(&mut self) -> &mut DenoiseState<'model> {
+        &mut self.state
+    }
+
+    /// Denoises some samples, appending any available output to `output`.
+    ///
+    /// `input` can have any length. It will be buffered internally, and processed once enough
+    /// samples have accumulated. See [`StreamingDenoiser::latency`] for how much output to
+    /// expect.
+    pub fn process(&mut self, mut input: &[f32], output: &mut Vec<f32>) {
+        let frame_size = self.state.frame_size();
+        self.in_count += input.len();
+        while !input.is_empty() {
+            let len = (frame_size - self.in_len).min(input.len());
+            self.in_buf[self.in_len..(self.in_len + len)].copy_from_slice(&input[..len]);
+            self.in_len += len;
+            input = &input[len..];
+
+            if self.in_len == frame_size {
+                self.process_buffered(output);
+            }
+        }
+    }
+
+    /// Denoises some samples, after checking that they are valid.
+    ///
+    /// This is like [`StreamingDenoiser::process`], except that it returns an error if `input`
+    /// contains NaNs or infinities. If there is an error, none of `input` is processed.
+    pub fn try_process(&mut self, input: &[f32], output: &mut Vec<f32>) -> Result<(), Error> {
+        check_finite(input)?;
+        self.process(input, output);
+        Ok(())
+    }
+
+    /// Finishes processing the stream, appending the remaining output to `output`.
+    ///
+    /// Any partial frame of input is padded with zeros and processed, so that in total the
+    /// number of output samples is the same as the number of input samples. After flushing, this
+    /// `StreamingDenoiser` can be used to process a new stream: the history of the old one is
+    /// forgotten (see [`DenoiseState::reset`]), but the settings are kept.
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        if self.in_count > 0 {
+            // Pad the partial frame with zeros. Because the output lags the input, we might need
+            // to process some extra frames of silence in order to get the rest of the output.
+            for x in &mut self.in_buf[self.in_len..] {
+                *x = 0.0;
+            }
+            while self.out_count < self.in_count + self.latency() {
+                self.process_buffered(output);
+                self.in_buf = [0.0; FRAME_SIZE];
+            }
+            // We probably produced a little too much output, from the padding.
+            let extra = self.out_count - self.in_count - self.latency();
+            output.truncate(output.len() - extra);
+        }
+
+        self.in_len = 0;
+        self.in_count = 0;
+        self.out_count = 0;
+        self.state.reset();
+    }
+
+    fn process_buffered(&mut self, output: &mut Vec<f32>) {
+        let frame_size = self.state.frame_size();
+        self.state
+            .process_frame(&mut self.out_buf[..frame_size], &self.in_buf[..frame_size]);
+        self.in_len = 0;
+
+        // The first `latency()` samples of output are discarded, so that the output is aligned
+        // with the input.
+        let skip = self
+            .latency()
+            .saturating_sub(self.out_count)
+            .min(frame_size);
+        output.extend_from_slice(&self.out_buf[skip..frame_size]);
+        self.out_count += frame_size;
+    }
+}
+
+impl Default for StreamingDenoiser<'static> {
+    fn default() -> StreamingDenoiser<'static> {
+        StreamingDenoiser::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference_input() -> Vec<f32> {
+        include_bytes!("../test_data/testing.raw")
+            .chunks_exact(2)
+            .map(|x| i16::from_le_bytes([x[0], x[1]]) as f32)
+            .collect()
+    }
+
+    #[test]
+    fn matches_process_frame() {
+        let input = reference_input();
+        let mut expected = Vec::new();
+        let mut out_buf = [0.0; FRAME_SIZE];
+        let mut state = DenoiseState::new();
+        for chunk in input.chunks_exact(FRAME_SIZE) {
+            state.process_frame(&mut out_buf, chunk);
+            expected.extend_from_slice(&out_buf);
+        }
+
+        let mut output = Vec::new();
+        let mut denoise = StreamingDenoiser::new();
+        let mut fed = 0;
+        for chunk in input.chunks(317) {
+            denoise.process(chunk, &mut output);
+            fed += chunk.len();
+            let expected_len = fed.saturating_sub(denoise.latency()) / FRAME_SIZE * FRAME_SIZE;
+            assert_eq!(output.len(), expected_len);
+        }
+        assert_eq!(
+            &output[..],
+            &expected[FRAME_SIZE..(output.len() + FRAME_SIZE)]
+        );
+
+        denoise.flush(&mut output);
+        assert_eq!(output.len(), input.len());
+    }
+
+    #[test]
+    fn sample_rates() {
+        for &rate in DenoiseState::SAMPLE_RATES {
+            let mut state = DenoiseState::new();
+            state.set_sample_rate(rate).set_mix(0.0);
+            assert_eq!(state.sample_rate(), rate);
+            assert_eq!(state.frame_size(), rate as usize / 100);
+            let mut denoise = StreamingDenoiser::from_state(state);
+
+            let input: Vec<f32> = (0..rate)
+                .map(|x| (x as f32 * 1000.0 * 2.0 * std::f32::consts::PI / rate as f32).sin())
+                .map(|x| x * 10_000.0)
+                .collect();
+            let mut output = Vec::new();
+            for chunk in input.chunks(100) {
+                denoise.process(chunk, &mut output);
+            }
+            denoise.flush(&mut output);
+            assert_eq!(output.len(), input.len());
+
+            // With no denoising, the output should be aligned with the input and almost the same
+            // (the high-pass filter changes it very slightly).
+            let skip = rate as usize / 10;
+            let err: f32 = input[skip..]
+                .iter()
+                .zip(&output[skip..])
+                .map(|(x, y)| (x - y) * (x - y))
+                .sum();
+            let energy: f32 = input[skip..].iter().map(|x| x * x).sum();
+            assert!(err < 1e-3 * energy);
+        }
+    }
+
+    #[test]
+    fn flush_lengths() {
+        for &len in &[
+            0,
+            1,
+            FRAME_SIZE - 1,
+            FRAME_SIZE,
+            FRAME_SIZE + 1,
+            3 * FRAME_SIZE,
+        ] {
+            let mut denoise = StreamingDenoiser::new();
+            let mut output = Vec::new();
+            denoise.process(&vec![100.0; len], &mut output);
+            denoise.flush(&mut output);
+            assert_eq!(output.len(), len);
+
+            // The denoiser can be reused after flushing.
+            denoise.process(&vec![100.0; len], &mut output);
+            denoise.flush(&mut output);
+            assert_eq!(output.len(), 2 * len);
+        }
+    }
+
+    #[test]
+    fn flush_forgets_stream() {
+        let input = reference_input();
+        let (first, second) = input.split_at(input.len() / 2);
+        let mut expected = Vec::new();
+        let mut denoise = StreamingDenoiser::new();
+        denoise.process(second, &mut expected);
+        denoise.flush(&mut expected);
+
+        // A stream processed after flushing comes out as if it were the first one.
+        let mut output = Vec::new();
+        let mut denoise = StreamingDenoiser::new();
+        denoise.process(first, &mut output);
+        denoise.flush(&mut output);
+        output.clear();
+        denoise.process(second, &mut output);
+        denoise.flush(&mut output);
+        assert_eq!(output, expected);
+    }
+}
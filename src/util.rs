@@ -1,6 +1,9 @@
 //! Utility functions.
 
-const TANSIG_TABLE: [f32; 201] = [
+#[cfg(not(any(feature = "std", test)))]
+use num_traits::Float;
+
+pub(crate) const TANSIG_TABLE: [f32; 201] = [
     0.000000, 0.039979, 0.079830, 0.119427, 0.158649, 0.197375, 0.235496, 0.272905, 0.309507,
     0.345214, 0.379949, 0.413644, 0.446244, 0.477700, 0.507977, 0.537050, 0.564900, 0.591519,
     0.616909, 0.641077, 0.664037, 0.685809, 0.706419, 0.725897, 0.744277, 0.761594, 0.777888,
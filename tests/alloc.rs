@@ -0,0 +1,115 @@
+//! Checks that processing doesn't allocate, using a global allocator that counts allocations.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use nnnoiseless::{DenoiseState, RnnModel, StreamingDenoiser};
+
+struct CountingAllocator;
+
+thread_local! {
+    // Only count allocations on the test's thread, since the test harness might be allocating
+    // on other threads.
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(|a| a.get());
+    f();
+    ALLOCATIONS.with(|a| a.get()) - before
+}
+
+fn test_input() -> Vec<f32> {
+    let bytes = include_bytes!("../test_data/testing.raw");
+    bytes
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32)
+        .collect()
+}
+
+#[test]
+fn process_frame() {
+    let input = test_input();
+    let input_i16: Vec<i16> = input.iter().map(|&x| x as i16).collect();
+    for &rate in DenoiseState::SAMPLE_RATES {
+        let mut state = DenoiseState::new();
+        state
+            .set_sample_rate(rate)
+            .set_max_attenuation(20.0)
+            .set_mix(0.8);
+        let frame_size = state.frame_size();
+        let mut out = vec![0.0; frame_size];
+        let mut out_i16 = vec![0; frame_size];
+        let silence = vec![0.0; frame_size];
+
+        assert_eq!(
+            allocations(|| {
+                state.process_frame(&mut out, &silence);
+                for (chunk, chunk_i16) in input
+                    .chunks_exact(frame_size)
+                    .zip(input_i16.chunks_exact(frame_size))
+                {
+                    state.process_frame_with_info(&mut out, chunk);
+                    state.try_process_frame(&mut out, chunk).unwrap();
+                    state.process_frame_i16(&mut out_i16, chunk_i16);
+                }
+                // Errors don't allocate either.
+                assert!(state.try_process_frame(&mut out, &[f32::NAN]).is_err());
+            }),
+            0
+        );
+    }
+}
+
+#[test]
+fn crossfade() {
+    let input = test_input();
+    let model = RnnModel::default();
+    let mut state = DenoiseState::new();
+    state.crossfade_to_model(&model, 50);
+    let mut out = vec![0.0; DenoiseState::FRAME_SIZE];
+    assert_eq!(
+        allocations(|| {
+            for chunk in input.chunks_exact(DenoiseState::FRAME_SIZE) {
+                state.process_frame(&mut out, chunk);
+            }
+        }),
+        0
+    );
+}
+
+#[test]
+fn streaming() {
+    let input = test_input();
+    let mut denoiser = StreamingDenoiser::new();
+    let mut output = Vec::with_capacity(input.len() + DenoiseState::FRAME_SIZE);
+    assert_eq!(
+        allocations(|| {
+            for chunk in input.chunks(1000) {
+                denoiser.process(chunk, &mut output);
+            }
+            denoiser.flush(&mut output);
+        }),
+        0
+    );
+    assert_eq!(output.len(), input.len());
+}
@@ -16,6 +16,93 @@ fn basic_usage() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn output_length() -> anyhow::Result<()> {
+    let tmp = assert_fs::TempDir::new()?;
+    let input = tmp.child("input.raw");
+    let output = tmp.child("output.raw");
+
+    // Lengths that aren't a multiple of the frame size shouldn't get truncated.
+    for &len in &[0, 100, 480, 1000, 4800] {
+        input.write_binary(&vec![0u8; len * 2])?;
+        let mut cmd = Command::cargo_bin("nnnoiseless")?;
+        cmd.arg(input.path()).arg(output.path());
+        cmd.assert().success();
+        assert_eq!(std::fs::metadata(output.path())?.len(), len as u64 * 2);
+
+        // For stereo input, the length is counted in frames of two samples.
+        let mut cmd = Command::cargo_bin("nnnoiseless")?;
+        cmd.arg("--channels=2").arg(input.path()).arg(output.path());
+        cmd.assert().success();
+        assert_eq!(std::fs::metadata(output.path())?.len(), len as u64 * 2);
+    }
+    Ok(())
+}
+
+#[test]
+fn native_sample_rate() -> anyhow::Result<()> {
+    let tmp = assert_fs::TempDir::new()?;
+    let input = tmp.child("input.raw");
+    let output = tmp.child("output.wav");
+    input.write_binary(&vec![0u8; 1000 * 2])?;
+
+    // 16kHz is supported natively, so the output should also be at 16kHz.
+    let mut cmd = Command::cargo_bin("nnnoiseless")?;
+    cmd.arg("--sample-rate=16000")
+        .arg(input.path())
+        .arg(output.path());
+    cmd.assert().success();
+    let wav = hound::WavReader::open(output.path())?;
+    assert_eq!(wav.spec().sample_rate, 16_000);
+    assert_eq!(wav.len(), 1000);
+
+    // 44.1kHz isn't supported natively, so it gets resampled to 48kHz.
+    let mut cmd = Command::cargo_bin("nnnoiseless")?;
+    cmd.arg("--sample-rate=44100")
+        .arg(input.path())
+        .arg(output.path());
+    cmd.assert().success();
+    let wav = hound::WavReader::open(output.path())?;
+    assert_eq!(wav.spec().sample_rate, 48_000);
+    Ok(())
+}
+
+#[test]
+fn max_attenuation() -> anyhow::Result<()> {
+    let mut cmd = Command::cargo_bin("nnnoiseless")?;
+    let tmp = assert_fs::TempDir::new()?;
+    let input = tmp.child("input.raw");
+    let output = tmp.child("output.raw");
+    input.write_binary(&vec![0u8; 480 * 10])?;
+
+    cmd.arg("--max-attenuation=20")
+        .arg(input.path())
+        .arg(output.path());
+    cmd.assert().success();
+    assert!(output.exists());
+
+    let mut cmd = Command::cargo_bin("nnnoiseless")?;
+    cmd.arg("--max-attenuation=lots")
+        .arg(input.path())
+        .arg(output.path());
+    cmd.assert().failure();
+    Ok(())
+}
+
+#[test]
+fn mix() -> anyhow::Result<()> {
+    let mut cmd = Command::cargo_bin("nnnoiseless")?;
+    let tmp = assert_fs::TempDir::new()?;
+    let input = tmp.child("input.raw");
+    let output = tmp.child("output.raw");
+    input.write_binary(&vec![0u8; 480 * 10])?;
+
+    cmd.arg("--mix=0.5").arg(input.path()).arg(output.path());
+    cmd.assert().success();
+    assert!(output.exists());
+    Ok(())
+}
+
 #[test]
 fn invalid_wav() -> anyhow::Result<()> {
     let mut cmd = Command::cargo_bin("nnnoiseless")?;
@@ -39,3 +126,70 @@ fn invalid_wav() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn rnnoise_text_model() -> anyhow::Result<()> {
+    let mut cmd = Command::cargo_bin("nnnoiseless")?;
+    let tmp = assert_fs::TempDir::new()?;
+    let input = tmp.child("input.raw");
+    let output = tmp.child("output.raw");
+    input.write_binary(&vec![0u8; 480 * 10])?;
+
+    cmd.arg("--model=test_data/sh.rnnn")
+        .arg(input.path())
+        .arg(output.path());
+    cmd.assert().success();
+    Ok(())
+}
+
+#[test]
+fn invalid_model() -> anyhow::Result<()> {
+    let mut cmd = Command::cargo_bin("nnnoiseless")?;
+    let tmp = assert_fs::TempDir::new()?;
+    let input = tmp.child("input.raw");
+    let output = tmp.child("output.raw");
+    let model = tmp.child("model.rnn");
+    input.write_binary(&vec![0u8; 480 * 10])?;
+    model.write_binary(&[42, 24, 0, 1, 2, 3])?;
+
+    cmd.arg("--model")
+        .arg(model.path())
+        .arg(input.path())
+        .arg(output.path());
+    cmd.assert().failure().stderr(predicates::str::contains(
+        "model data ended unexpectedly in layer input_dense (at byte 3)",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn model_info() -> anyhow::Result<()> {
+    let mut cmd = Command::cargo_bin("nnnoiseless")?;
+    cmd.arg("model-info").arg("test_data/sh.rnnn");
+    cmd.assert()
+        .success()
+        .stdout(predicates::str::contains("Parameters: 87503"))
+        .stdout(predicates::str::contains(
+            "noise_gru       Gru         90      48  Relu     I8",
+        ))
+        .stdout(predicates::str::contains("input_dense, vad_gru, features"))
+        .stdout(predicates::str::contains("Voice activity: vad_output"));
+    Ok(())
+}
+
+#[test]
+fn builtin_model() -> anyhow::Result<()> {
+    let mut cmd = Command::cargo_bin("nnnoiseless")?;
+    cmd.arg("model-info").arg("builtin:rnnoise");
+    cmd.assert()
+        .success()
+        .stdout(predicates::str::contains("Parameters: 87503"));
+
+    let mut cmd = Command::cargo_bin("nnnoiseless")?;
+    cmd.arg("model-info").arg("builtin:nonexistent");
+    cmd.assert().failure().stderr(predicates::str::contains(
+        "Unknown built-in model \"nonexistent\" (available models: rnnoise",
+    ));
+    Ok(())
+}
@@ -1,5 +1,10 @@
 import numpy as np
 import re
+import struct
+import zlib
+
+# The numbers that identify the weight precisions in nnnoiseless's container format.
+_PRECISIONS = {'i8': 0, 'i16': 1, 'f32': 2}
 
 def _append_vector(bs, vector):
     vector = np.reshape(vector, (-1))
@@ -39,11 +44,64 @@ def _append_layer(bs, layer):
         _append_vector(bs, weights[i])
 
 
-def dump_model(model, filename):
+def _append_layer_with_precision(bs, layer, precision):
+    weights = layer.get_weights()
+    act = _activation(layer)
+    nb_inputs = weights[0].shape[0]
+    nb_neurons = weights[0].shape[1]
+
+    if len(weights) > 2:
+        # This is a GRU layer.
+        nb_neurons = int(nb_neurons / 3)
+
+    vectors = [np.reshape(w, (-1)) for w in weights]
+    if precision == 'f32':
+        scale = 1.0
+    elif precision == 'i16':
+        # Use the whole range of an i16 for the largest weight in the layer.
+        largest = max(np.max(np.abs(v)) for v in vectors)
+        scale = largest / 32767 if largest > 0 else 1.0
+    else:
+        scale = 1.0 / 256
+
+    bs.extend(struct.pack('<HHBBf', nb_inputs, nb_neurons, act, _PRECISIONS[precision], scale))
+    for v in vectors:
+        if precision == 'f32':
+            bs.extend(v.astype('<f4').tobytes())
+        elif precision == 'i16':
+            bs.extend(np.clip(np.round(v / scale), -32768, 32767).astype('<i2').tobytes())
+        else:
+            _append_vector(bs, v)
+
+def _container(payload):
+    bs = bytearray(b'nnnm')
+    # The format version.
+    bs.extend(struct.pack('<H', 1))
+    arch = b'rnnoise'
+    bs.extend(struct.pack('<I', len(arch)))
+    bs.extend(arch)
+    # The number of metadata entries.
+    bs.extend(struct.pack('<I', 0))
+    bs.extend(struct.pack('<I', len(payload)))
+    bs.extend(payload)
+    bs.extend(struct.pack('<I', zlib.crc32(bytes(bs)) & 0xffffffff))
+    return bs
+
+def dump_model(model, filename, precision=None):
+    """Saves the model's weights in a format that nnnoiseless can read.
+
+    By default, the weights are quantized to bytes and saved in the legacy format, which can be
+    read by all versions of nnnoiseless. If `precision` is one of 'i8', 'i16' or 'f32', they are
+    saved with that precision in the container format instead.
+    """
     with open(filename, 'wb') as file:
         bs = bytearray()
         for i, layer in enumerate(model.layers):
             if len(layer.get_weights()) > 0:
-                _append_layer(bs, layer)
+                if precision is None:
+                    _append_layer(bs, layer)
+                else:
+                    _append_layer_with_precision(bs, layer, precision)
+        if precision is not None:
+            bs = _container(bs)
         file.write(bs)
-